@@ -1,7 +1,10 @@
+use super::query::STDIN_QUERY;
 use std::cmp::PartialEq;
 use std::default::Default;
+use std::env;
 use std::error;
 use std::fmt;
+use std::io::{self, IsTerminal};
 
 #[derive(Debug, PartialEq)]
 struct CliOption {
@@ -59,7 +62,6 @@ const fn suboption(
     }
 }
 
-/*
 const fn suboption_long(
     group: &'static CliOption,
     long: &'static str,
@@ -70,9 +72,19 @@ const fn suboption_long(
         long,
         comment,
         group: Some(group),
+        takes_value: false,
+    }
+}
+
+const fn option_long(long: &'static str, comment: &'static str) -> CliOption {
+    CliOption {
+        short: None,
+        long,
+        comment,
+        group: None,
+        takes_value: false,
     }
 }
-*/
 
 const fn option_long_value(long: &'static str, comment: &'static str) -> CliOption {
     CliOption {
@@ -84,16 +96,65 @@ const fn option_long_value(long: &'static str, comment: &'static str) -> CliOpti
     }
 }
 
+const fn suboption_long_value(
+    group: &'static CliOption,
+    long: &'static str,
+    comment: &'static str,
+) -> CliOption {
+    CliOption {
+        short: None,
+        long,
+        comment,
+        group: Some(group),
+        takes_value: true,
+    }
+}
+
+pub const ENV_DEFAULT_API: &str = "PACMAN_BLAME_DEFAULT";
+// see no-color.org, an empty value doesn't count
+const ENV_NO_COLOR: &str = "NO_COLOR";
+
 const OPT_HELP: CliOption = option("-h", "--help", "display on any item");
+const OPT_VERSION: CliOption = option("-V", "--version", "print the version of pacman-blame");
 const OPT_VERBOSE: CliOption = option("-v", "--verbose", "print information of what is going on");
-const OPT_COLOR: CliOption = option("-c", "--color", "use colors on terminals that support them");
+const OPT_COLOR: CliOption = option("-c", "--color", "use colors even when not on a terminal");
+const OPT_NO_COLOR: CliOption = option_long("--no-color", "never use colors, wins over --color");
+const OPT_IGNORE_CASE: CliOption = option(
+    "-i",
+    "--ignore-case",
+    "match package names regardless of case",
+);
 const OPT_FORMAT: CliOption = option_long_value("--format", "print using the format");
+const OPT_XARGS_SAFE: CliOption = option_long(
+    "--xargs-safe",
+    "fail on printed lines with whitespace xargs would split on, dependents get a line each",
+);
+const OPT_JSON: CliOption = option_long("--json", "print the packages as a json array");
+const OPT_PRINT0: CliOption = option_long(
+    "--print0",
+    "end every printed package with a null byte instead of a newline, for xargs -0",
+);
+const OPT_COUNT: CliOption = option_long("--count", "only print how many packages would be listed");
+const OPT_LIST_FORMATS: CliOption =
+    option_long("--list-formats", "list the tokens understood by --format");
+const OPT_CHECK_FORMAT: CliOption = option_long(
+    "--check-format",
+    "only check that the --format compiles, print ok or the error",
+);
+const OPT_ON_CONFLICT: CliOption = option_long_value(
+    "--on-conflict",
+    "what to do with repeated options: skip|error (default error)",
+);
+const OPT_BENCHMARK: CliOption = option_long(
+    "--benchmark",
+    "print the time spent in each phase to stderr",
+);
 const OPT_API_LIST: CliOption = option("-L", "--list", "utilities for listing packages");
 const OPT_API_LIST_REQUIRED_BY: CliOption = suboption(
     &OPT_API_LIST,
     "-r",
     "--required-by",
-    "show packages that requires this package",
+    "show packages that (transitively) require this package",
 );
 
 const OPT_API_LIST_EXPLICIT: CliOption = suboption(
@@ -109,19 +170,558 @@ const OPT_API_LIST_DEPENDENCY: CliOption = suboption(
     "filter on packages installed as a dependency",
 );
 
-const OPT_LIST: [CliOption; 8] = [
+const OPT_API_LIST_REVERSE_DEPS_MATRIX: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--reverse-deps-matrix",
+    "print a csv of which packages (transitively) require each queried package",
+);
+
+const OPT_API_LIST_REASON_SUMMARY: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--reason-summary",
+    "only print how many of the packages are explicit and dependencies",
+);
+
+const OPT_API_LIST_EXPLICIT_LEAVES: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--explicit-leaves",
+    "only explicitly installed packages that nothing depends on",
+);
+
+const OPT_API_LIST_NO_TRANSITIVE: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--no-transitive",
+    "only show direct dependents instead of the whole chain",
+);
+
+const OPT_API_LIST_REQBY_COUNTS: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--reqby-counts",
+    "annotate each dependent with how many packages require it in turn",
+);
+
+const OPT_API_LIST_SORT_REQBY: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--sort-reqby",
+    "sort the dependents by name|reason|depth",
+);
+
+const OPT_API_LIST_GROUP_MEMBERS: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--group-members",
+    "print each group followed by its members",
+);
+
+const OPT_API_LIST_PRUNE: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--prune",
+    "leave out these comma separated packages and everything depending on them",
+);
+
+const OPT_API_LIST_SUGGEST_REASONS: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--suggest-reasons",
+    "suggest install reasons that might be better, nothing is changed",
+);
+
+const OPT_API_LIST_TIME_MACHINE: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--time-machine",
+    "group the packages into sessions by their install date",
+);
+
+const OPT_API_LIST_SESSION_GAP: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--session-gap",
+    "seconds between two installs that start a new session, default 600",
+);
+
+const OPT_API_LIST_CRITICALITY: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--criticality",
+    "rank the packages by how much ends up depending on them",
+);
+
+const OPT_API_LIST_DIFF: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--diff",
+    "show the packages in only one of two groups of packages separated by a -- after --diff",
+);
+
+const OPT_API_LIST_WHY: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--why",
+    "with --required-by, only show the shortest chain to an explicit package",
+);
+
+const OPT_API_LIST_MAX_FANOUT: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--max-fanout",
+    "the N most (transitively) required packages, slow as every package is walked",
+);
+
+const OPT_API_LIST_ADJACENCY_JSON: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--adjacency-json",
+    "with --required-by, print a json object of each package's direct dependents",
+);
+
+const OPT_API_LIST_MERMAID: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--mermaid",
+    "with --required-by, print a mermaid diagram of the dependents",
+);
+
+const OPT_API_LIST_DEPENDS: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--depends",
+    "show packages that this package (transitively) depends on",
+);
+
+const OPT_API_LIST_INCLUDE_DEPS_OF: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--include-deps-of",
+    "also list this package and everything it (transitively) depends on",
+);
+
+const OPT_API_LIST_DEPTH: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--depth",
+    "walk at most this many levels of dependents or dependencies, 1 is only direct ones",
+);
+
+const OPT_API_LIST_IDLE_PACKAGES: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--idle-packages",
+    "dependencies nothing requires whose files weren't used for this many days",
+);
+
+const OPT_API_LIST_SUBTRACT_FILE: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--subtract-file",
+    "leave the packages named in this file, one per line, out of the results",
+);
+
+const OPT_API_LIST_SECTIONS: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--sections",
+    "with --required-by, print each package followed by its dependents on their own lines",
+);
+
+const OPT_API_LIST_CLASSIFY: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--classify",
+    "label the packages leaf, branch or hub by how many packages require them",
+);
+
+const OPT_API_LIST_HUB_THRESHOLD: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--hub-threshold",
+    "packages required by more than this many are hubs, default 5",
+);
+
+const OPT_API_LIST_REACHES: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--reaches",
+    "whether the packages (transitively) depend on this package, and through what",
+);
+
+const OPT_API_LIST_TREE: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--tree",
+    "with --required-by, draw the dependents as a tree",
+);
+
+const OPT_API_LIST_REQBY_REPO: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--reqby-repo",
+    "with --required-by, only show dependents from this repo, or foreign",
+);
+
+const OPT_API_LIST_SORT: CliOption = suboption_long_value(
+    &OPT_API_LIST,
+    "--sort",
+    "order the packages by name, or by size with the largest first",
+);
+
+const OPT_API_LIST_OPTIONAL: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--optional",
+    "with --required-by, also follow optional dependencies, colored cyan or marked in a --tree",
+);
+
+const OPT_API_LIST_STREAM: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--stream",
+    "with --required-by, print the dependents as they are found",
+);
+
+const OPT_API_LIST_ORPHANS: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--orphans",
+    "only list dependencies that nothing requires anymore, like pacman -Qdt",
+);
+
+const OPT_API_LIST_MERGE: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--merge",
+    "with --required-by, list the dependents of all queried packages together, each once",
+);
+
+const OPT_API_LIST_ROOT_PATHS: CliOption = suboption_long(
+    &OPT_API_LIST,
+    "--root-paths",
+    "with --required-by, mark the dependents an explicit package (transitively) requires",
+);
+
+// separates the two groups of packages compared by --diff
+const QUERY_SEPARATOR: &str = "--";
+// everything after it is a query, even if it looks like an option
+const END_OF_OPTIONS: &str = "--";
+
+const OPT_LIST: [CliOption; 53] = [
     OPT_COLOR,
+    OPT_NO_COLOR,
     OPT_HELP,
+    OPT_VERSION,
+    OPT_LIST_FORMATS,
+    OPT_CHECK_FORMAT,
     OPT_VERBOSE,
+    OPT_IGNORE_CASE,
     OPT_FORMAT,
+    OPT_XARGS_SAFE,
+    OPT_JSON,
+    OPT_PRINT0,
+    OPT_COUNT,
+    OPT_ON_CONFLICT,
+    OPT_BENCHMARK,
     OPT_API_LIST,
     OPT_API_LIST_EXPLICIT,
     OPT_API_LIST_DEPENDENCY,
     OPT_API_LIST_REQUIRED_BY,
+    OPT_API_LIST_REVERSE_DEPS_MATRIX,
+    OPT_API_LIST_REASON_SUMMARY,
+    OPT_API_LIST_EXPLICIT_LEAVES,
+    OPT_API_LIST_NO_TRANSITIVE,
+    OPT_API_LIST_REQBY_COUNTS,
+    OPT_API_LIST_SORT_REQBY,
+    OPT_API_LIST_GROUP_MEMBERS,
+    OPT_API_LIST_PRUNE,
+    OPT_API_LIST_SUGGEST_REASONS,
+    OPT_API_LIST_TIME_MACHINE,
+    OPT_API_LIST_SESSION_GAP,
+    OPT_API_LIST_CRITICALITY,
+    OPT_API_LIST_DIFF,
+    OPT_API_LIST_WHY,
+    OPT_API_LIST_MAX_FANOUT,
+    OPT_API_LIST_ADJACENCY_JSON,
+    OPT_API_LIST_MERMAID,
+    OPT_API_LIST_DEPENDS,
+    OPT_API_LIST_INCLUDE_DEPS_OF,
+    OPT_API_LIST_DEPTH,
+    OPT_API_LIST_IDLE_PACKAGES,
+    OPT_API_LIST_SUBTRACT_FILE,
+    OPT_API_LIST_SECTIONS,
+    OPT_API_LIST_CLASSIFY,
+    OPT_API_LIST_HUB_THRESHOLD,
+    OPT_API_LIST_REACHES,
+    OPT_API_LIST_TREE,
+    OPT_API_LIST_REQBY_REPO,
+    OPT_API_LIST_SORT,
+    OPT_API_LIST_OPTIONAL,
+    OPT_API_LIST_STREAM,
+    OPT_API_LIST_ORPHANS,
+    OPT_API_LIST_MERGE,
+    OPT_API_LIST_ROOT_PATHS,
+];
+
+// at most one option of each group can be given
+const EXCLUSIVE_OPTIONS: [&[&CliOption]; 4] = [
+    // what the listing shows
+    &[
+        &OPT_API_LIST_REQUIRED_BY,
+        &OPT_API_LIST_REVERSE_DEPS_MATRIX,
+        &OPT_API_LIST_DEPENDS,
+        &OPT_API_LIST_REASON_SUMMARY,
+        &OPT_API_LIST_SUGGEST_REASONS,
+        &OPT_API_LIST_TIME_MACHINE,
+        &OPT_API_LIST_CRITICALITY,
+        &OPT_API_LIST_DIFF,
+        &OPT_API_LIST_MAX_FANOUT,
+        &OPT_API_LIST_IDLE_PACKAGES,
+        &OPT_API_LIST_CLASSIFY,
+        &OPT_API_LIST_REACHES,
+        &OPT_API_LIST_GROUP_MEMBERS,
+    ],
+    // how the packages or their dependents are laid out
+    &[
+        &OPT_JSON,
+        &OPT_API_LIST_WHY,
+        &OPT_API_LIST_ADJACENCY_JSON,
+        &OPT_API_LIST_MERMAID,
+        &OPT_API_LIST_TREE,
+        &OPT_API_LIST_STREAM,
+        &OPT_API_LIST_MERGE,
+        &OPT_API_LIST_SECTIONS,
+    ],
+    // what is printed for every package
+    &[&OPT_FORMAT, &OPT_JSON, &OPT_COUNT],
+    // which packages of a plain listing are kept
+    &[&OPT_API_LIST_EXPLICIT_LEAVES, &OPT_API_LIST_ORPHANS],
+];
+
+// what the options shaping the dependents of --required-by need
+const REQUIRED_BY: &[&CliOption] = &[&OPT_API_LIST_REQUIRED_BY];
+// what the options shaping the walk through the dependency graph need
+const WALKS: &[&CliOption] = &[
+    &OPT_API_LIST_REQUIRED_BY,
+    &OPT_API_LIST_REVERSE_DEPS_MATRIX,
+    &OPT_API_LIST_DEPENDS,
+];
+// leaves and orphans have no dependents, so listings about dependents make no sense for them
+const DEPENDENT_LISTINGS: &[&CliOption] = &[
+    &OPT_API_LIST_REQUIRED_BY,
+    &OPT_API_LIST_REVERSE_DEPS_MATRIX,
+    &OPT_API_LIST_DEPENDS,
+    &OPT_API_LIST_SUGGEST_REASONS,
+    &OPT_API_LIST_CRITICALITY,
+    &OPT_API_LIST_MAX_FANOUT,
+];
+
+// an option of the listing, if given, needs one of `needs` unless that is empty, and none of
+// `excludes`
+struct Compatibility {
+    option: &'static CliOption,
+    needs: &'static [&'static CliOption],
+    excludes: &'static [&'static CliOption],
+}
+
+const COMPATIBILITY: [Compatibility; 24] = [
+    Compatibility {
+        option: &OPT_JSON,
+        needs: &[],
+        excludes: &[
+            &OPT_API_LIST_REVERSE_DEPS_MATRIX,
+            &OPT_API_LIST_DEPENDS,
+            &OPT_API_LIST_REASON_SUMMARY,
+            &OPT_API_LIST_SUGGEST_REASONS,
+            &OPT_API_LIST_TIME_MACHINE,
+            &OPT_API_LIST_CRITICALITY,
+            &OPT_API_LIST_DIFF,
+            &OPT_API_LIST_MAX_FANOUT,
+            &OPT_API_LIST_IDLE_PACKAGES,
+            &OPT_API_LIST_CLASSIFY,
+            &OPT_API_LIST_REACHES,
+            &OPT_API_LIST_GROUP_MEMBERS,
+            &OPT_API_LIST_REQBY_COUNTS,
+            &OPT_API_LIST_ROOT_PATHS,
+        ],
+    },
+    // only listings printing a package per line can be counted
+    Compatibility {
+        option: &OPT_COUNT,
+        needs: &[],
+        excludes: &[
+            &OPT_API_LIST_REVERSE_DEPS_MATRIX,
+            &OPT_API_LIST_REASON_SUMMARY,
+            &OPT_API_LIST_TIME_MACHINE,
+            &OPT_API_LIST_REACHES,
+            &OPT_API_LIST_GROUP_MEMBERS,
+            &OPT_API_LIST_WHY,
+            &OPT_API_LIST_ADJACENCY_JSON,
+            &OPT_API_LIST_MERMAID,
+            &OPT_API_LIST_TREE,
+            &OPT_API_LIST_STREAM,
+            &OPT_API_LIST_SECTIONS,
+            &OPT_API_LIST_ROOT_PATHS,
+        ],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_WHY,
+        needs: REQUIRED_BY,
+        excludes: &[
+            &OPT_API_LIST_REQBY_COUNTS,
+            &OPT_API_LIST_SORT_REQBY,
+            &OPT_API_LIST_NO_TRANSITIVE,
+            &OPT_API_LIST_REQBY_REPO,
+            &OPT_API_LIST_ROOT_PATHS,
+        ],
+    },
+    // the graphs keep every dependent, leaving one out with -e or -d would cut off those above it
+    Compatibility {
+        option: &OPT_API_LIST_ADJACENCY_JSON,
+        needs: REQUIRED_BY,
+        excludes: &[
+            &OPT_API_LIST_EXPLICIT,
+            &OPT_API_LIST_DEPENDENCY,
+            &OPT_API_LIST_REQBY_COUNTS,
+            &OPT_API_LIST_SORT_REQBY,
+            &OPT_API_LIST_REQBY_REPO,
+            &OPT_API_LIST_ROOT_PATHS,
+        ],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_MERMAID,
+        needs: REQUIRED_BY,
+        excludes: &[
+            &OPT_API_LIST_EXPLICIT,
+            &OPT_API_LIST_DEPENDENCY,
+            &OPT_API_LIST_REQBY_COUNTS,
+            &OPT_API_LIST_SORT_REQBY,
+            &OPT_API_LIST_REQBY_REPO,
+            &OPT_API_LIST_ROOT_PATHS,
+        ],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_TREE,
+        needs: REQUIRED_BY,
+        excludes: &[
+            &OPT_API_LIST_EXPLICIT,
+            &OPT_API_LIST_DEPENDENCY,
+            &OPT_API_LIST_REQBY_COUNTS,
+            &OPT_API_LIST_SORT_REQBY,
+            &OPT_API_LIST_REQBY_REPO,
+            &OPT_API_LIST_ROOT_PATHS,
+        ],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_STREAM,
+        needs: REQUIRED_BY,
+        excludes: &[&OPT_API_LIST_SORT_REQBY],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_MERGE,
+        needs: REQUIRED_BY,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_SECTIONS,
+        needs: REQUIRED_BY,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_REQBY_COUNTS,
+        needs: REQUIRED_BY,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_SORT_REQBY,
+        needs: REQUIRED_BY,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_REQBY_REPO,
+        needs: REQUIRED_BY,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_OPTIONAL,
+        needs: REQUIRED_BY,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_ROOT_PATHS,
+        needs: REQUIRED_BY,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_NO_TRANSITIVE,
+        needs: WALKS,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_DEPTH,
+        needs: WALKS,
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_PRUNE,
+        needs: WALKS,
+        excludes: &[],
+    },
+    // the graphs and chains need every package they pass through, the rest list only names
+    Compatibility {
+        option: &OPT_API_LIST_SUBTRACT_FILE,
+        needs: &[],
+        excludes: &[
+            &OPT_API_LIST_REVERSE_DEPS_MATRIX,
+            &OPT_API_LIST_DEPENDS,
+            &OPT_API_LIST_WHY,
+            &OPT_API_LIST_ADJACENCY_JSON,
+            &OPT_API_LIST_MERMAID,
+            &OPT_API_LIST_TREE,
+        ],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_HUB_THRESHOLD,
+        needs: &[&OPT_API_LIST_CLASSIFY],
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_SESSION_GAP,
+        needs: &[&OPT_API_LIST_TIME_MACHINE],
+        excludes: &[],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_EXPLICIT_LEAVES,
+        needs: &[],
+        excludes: DEPENDENT_LISTINGS,
+    },
+    Compatibility {
+        option: &OPT_API_LIST_ORPHANS,
+        needs: &[],
+        excludes: DEPENDENT_LISTINGS,
+    },
+    // leaves are explicit and orphans dependencies, asking for the other reason leaves nothing
+    Compatibility {
+        option: &OPT_API_LIST_EXPLICIT_LEAVES,
+        needs: &[],
+        excludes: &[&OPT_API_LIST_DEPENDENCY],
+    },
+    Compatibility {
+        option: &OPT_API_LIST_ORPHANS,
+        needs: &[],
+        excludes: &[&OPT_API_LIST_EXPLICIT],
+    },
 ];
 
+// given holds the options of the listing in the order they were given
+fn check_compatibility(given: &[&CliOption]) -> Result<(), ArgError> {
+    let is_given = |option: &CliOption| given.contains(&option);
+    for group in EXCLUSIVE_OPTIONS {
+        let mut in_group = given.iter().filter(|g| group.contains(g));
+        let Some(first) = in_group.next() else {
+            continue;
+        };
+        if let Some(second) = in_group.find(|g| *g != first) {
+            return Err(ArgError::Incompatible(
+                first.long.to_string(),
+                second.long.to_string(),
+            ));
+        }
+    }
+    for rule in COMPATIBILITY.iter().filter(|rule| is_given(rule.option)) {
+        if !rule.needs.is_empty() && !rule.needs.iter().any(|option| is_given(option)) {
+            return Err(ArgError::Needs(
+                rule.option.long.to_string(),
+                rule.needs
+                    .iter()
+                    .map(|option| option.long.to_string())
+                    .collect(),
+            ));
+        }
+        if let Some(excluded) = rule.excludes.iter().find(|option| is_given(option)) {
+            return Err(ArgError::Incompatible(
+                rule.option.long.to_string(),
+                excluded.long.to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn is_option<S: AsRef<str>>(option: &S) -> bool {
-    option.as_ref().starts_with("-")
+    option.as_ref().starts_with("-") && option.as_ref() != STDIN_QUERY
 }
 
 fn is_long_option<S: AsRef<str>>(option: &S) -> bool {
@@ -150,12 +750,68 @@ fn split_short<I: IntoIterator<Item = String>>(options: I) -> impl IntoIterator<
         .flatten()
 }
 
+// the first -- ends the options, unless --diff came before it and it separates the groups, then
+// the next one does
+fn split_end_of_options<I: IntoIterator<Item = String>>(args: I) -> (Vec<String>, Vec<String>) {
+    let mut options: Vec<_> = args.into_iter().collect();
+    let mut diff_pending = false;
+    let end = options.iter().position(|arg| {
+        if expand_long(arg.clone()).is_ok_and(|arg| OPT_API_LIST_DIFF == arg) {
+            diff_pending = true;
+        } else if arg == QUERY_SEPARATOR && diff_pending {
+            diff_pending = false;
+            return false;
+        }
+        arg == END_OF_OPTIONS
+    });
+
+    match end {
+        Some(end) => {
+            let literal = options.split_off(end + 1);
+            options.pop();
+            (options, literal)
+        }
+        None => (options, Vec::new()),
+    }
+}
+
+// an unambiguous prefix of a long option stands for the whole option, as --req for --required-by
+fn expand_long(option: String) -> Result<String, ArgError> {
+    if !is_long_option(&option) || option == QUERY_SEPARATOR {
+        return Ok(option);
+    }
+    let (name, value) = match option.split_once("=") {
+        Some((name, value)) => (name, Some(value)),
+        None => (option.as_str(), None),
+    };
+    if OPT_LIST.iter().any(|opt| opt.long == name) {
+        return Ok(option);
+    }
+
+    let candidates: Vec<_> = OPT_LIST
+        .iter()
+        .map(|opt| opt.long)
+        .filter(|long| long.starts_with(name))
+        .collect();
+    match candidates.as_slice() {
+        [] => Ok(option),
+        [long] => Ok(match value {
+            Some(value) => format!("{}={}", long, value),
+            None => long.to_string(),
+        }),
+        _ => Err(ArgError::AmbiguousOption(
+            name.to_string(),
+            candidates.iter().map(|long| long.to_string()).collect(),
+        )),
+    }
+}
+
 fn expand_short<I: IntoIterator<Item = String>>(
     options: I,
 ) -> impl IntoIterator<Item = Result<String, ArgError>> {
     options.into_iter().map(|option| {
         if !is_short_option(&option) {
-            Ok(option)
+            expand_long(option)
         } else {
             OPT_LIST
                 .iter()
@@ -172,12 +828,155 @@ fn expand_short<I: IntoIterator<Item = String>>(
     })
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ConflictPolicy {
+    Skip,
+    Error,
+}
+
+impl ConflictPolicy {
+    fn parse(value: &str) -> Result<ConflictPolicy, ArgError> {
+        match value {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "error" => Ok(ConflictPolicy::Error),
+            _ => Err(ArgError::InvalidValue(
+                OPT_ON_CONFLICT.long.to_string(),
+                value.to_string(),
+            )),
+        }
+    }
+
+    fn set_flag(self, flag: &mut bool, option: String) -> Result<(), ArgError> {
+        if !*flag {
+            *flag = true;
+            Ok(())
+        } else if self == ConflictPolicy::Skip {
+            Ok(())
+        } else {
+            Err(ArgError::DuplicateOption(option))
+        }
+    }
+
+    fn set_value<T: PartialEq>(
+        self,
+        slot: &mut Option<T>,
+        option: &str,
+        value: T,
+    ) -> Result<(), ArgError> {
+        match slot {
+            None => {
+                *slot = Some(value);
+                Ok(())
+            }
+            // repeating the same value is only redundant, a different one is a conflict
+            Some(old) if self == ConflictPolicy::Skip && *old == value => Ok(()),
+            Some(_) => Err(ArgError::DuplicateOption(option.to_string())),
+        }
+    }
+}
+
+// the policy must be known before the first duplicate is seen, so it is looked up ahead of parsing
+fn find_conflict_policy(options: &[Result<String, ArgError>]) -> Result<ConflictPolicy, ArgError> {
+    let mut policy = None;
+    for option in options
+        .iter()
+        .filter_map(|option| option.as_ref().ok())
+        .filter(|option| OPT_ON_CONFLICT == *option)
+    {
+        let (prefix, value) = option
+            .split_once("=")
+            .expect("this has already been verified");
+        // the policy is not known yet, so only a differing repeat is a conflict
+        ConflictPolicy::Skip.set_value(&mut policy, prefix, value.to_string())?;
+    }
+
+    policy
+        .map(|value| ConflictPolicy::parse(&value))
+        .unwrap_or(Ok(ConflictPolicy::Error))
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReqBySort {
+    Name,
+    Reason,
+    Depth,
+}
+
+impl ReqBySort {
+    fn parse(value: &str) -> Result<ReqBySort, ArgError> {
+        match value {
+            "name" => Ok(ReqBySort::Name),
+            "reason" => Ok(ReqBySort::Reason),
+            "depth" => Ok(ReqBySort::Depth),
+            _ => Err(ArgError::InvalidValue(
+                OPT_API_LIST_SORT_REQBY.long.to_string(),
+                value.to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PackageSort {
+    Name,
+    Size,
+}
+
+impl PackageSort {
+    fn parse(value: &str) -> Result<PackageSort, ArgError> {
+        match value {
+            "name" => Ok(PackageSort::Name),
+            "size" => Ok(PackageSort::Size),
+            _ => Err(ArgError::InvalidValue(
+                OPT_API_LIST_SORT.long.to_string(),
+                value.to_string(),
+            )),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ApiList {
     pub queries: Vec<String>,
     pub explicit: bool,
     pub dependency: bool,
     pub required_by: bool,
+    pub reverse_deps_matrix: bool,
+    pub reason_summary: bool,
+    pub explicit_leaves: bool,
+    pub no_transitive: bool,
+    pub reqby_counts: bool,
+    pub sort_reqby: Option<ReqBySort>,
+    pub group_members: bool,
+    pub prune: Option<String>,
+    pub suggest_reasons: bool,
+    pub time_machine: bool,
+    pub session_gap: Option<i64>,
+    pub criticality: bool,
+    pub diff: bool,
+    pub why: bool,
+    pub max_fanout: Option<usize>,
+    pub adjacency_json: bool,
+    pub mermaid: bool,
+    pub depends: bool,
+    pub include_deps_of: Option<String>,
+    pub depth: Option<usize>,
+    pub idle_packages: Option<i64>,
+    pub subtract_file: Option<String>,
+    pub sections: bool,
+    pub classify: bool,
+    pub hub_threshold: Option<usize>,
+    pub reaches: Option<String>,
+    pub tree: bool,
+    pub reqby_repo: Option<String>,
+    pub sort: Option<PackageSort>,
+    pub optional: bool,
+    pub stream: bool,
+    pub orphans: bool,
+    pub merge: bool,
+    pub root_paths: bool,
+    // the packages after the separator, if there was one
+    pub diff_queries: Option<Vec<String>>,
 }
 
 impl ApiList {
@@ -187,38 +986,237 @@ impl ApiList {
             explicit: false,
             dependency: false,
             required_by: false,
+            reverse_deps_matrix: false,
+            reason_summary: false,
+            explicit_leaves: false,
+            no_transitive: false,
+            reqby_counts: false,
+            sort_reqby: None,
+            group_members: false,
+            prune: None,
+            suggest_reasons: false,
+            time_machine: false,
+            session_gap: None,
+            criticality: false,
+            diff: false,
+            why: false,
+            max_fanout: None,
+            adjacency_json: false,
+            mermaid: false,
+            depends: false,
+            include_deps_of: None,
+            depth: None,
+            idle_packages: None,
+            subtract_file: None,
+            sections: false,
+            classify: false,
+            hub_threshold: None,
+            reaches: None,
+            tree: false,
+            reqby_repo: None,
+            sort: None,
+            optional: false,
+            stream: false,
+            orphans: false,
+            merge: false,
+            root_paths: false,
+            diff_queries: None,
         }
     }
-    fn add_option(mut self, option: String) -> Result<Api, ArgError> {
+    fn add_option(mut self, option: String, policy: ConflictPolicy) -> Result<Api, ArgError> {
         match option.as_str() {
             opt if OPT_HELP == opt => Ok(Api::HelpWith(OPT_API_LIST.long.to_string())),
             opt if OPT_API_LIST_EXPLICIT == opt => {
-                if !self.explicit {
-                    self.explicit = true;
-                    Ok(Api::List(self))
-                } else {
-                    Err(ArgError::DuplicateOption(option))
-                }
+                policy.set_flag(&mut self.explicit, option)?;
+                Ok(Api::List(Box::new(self)))
             }
             opt if OPT_API_LIST_DEPENDENCY == opt => {
-                if !self.dependency {
-                    self.dependency = true;
-                    Ok(Api::List(self))
-                } else {
-                    Err(ArgError::DuplicateOption(option))
-                }
+                policy.set_flag(&mut self.dependency, option)?;
+                Ok(Api::List(Box::new(self)))
             }
             opt if OPT_API_LIST_REQUIRED_BY == opt => {
-                if !self.required_by {
-                    self.required_by = true;
-                    Ok(Api::List(self))
-                } else {
-                    Err(ArgError::DuplicateOption(option))
-                }
+                policy.set_flag(&mut self.required_by, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_REVERSE_DEPS_MATRIX == opt => {
+                policy.set_flag(&mut self.reverse_deps_matrix, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_REASON_SUMMARY == opt => {
+                policy.set_flag(&mut self.reason_summary, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_EXPLICIT_LEAVES == opt => {
+                policy.set_flag(&mut self.explicit_leaves, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_NO_TRANSITIVE == opt => {
+                policy.set_flag(&mut self.no_transitive, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_REQBY_COUNTS == opt => {
+                policy.set_flag(&mut self.reqby_counts, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_SORT_REQBY == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                policy.set_value(&mut self.sort_reqby, prefix, ReqBySort::parse(value)?)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_GROUP_MEMBERS == opt => {
+                policy.set_flag(&mut self.group_members, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_PRUNE == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                policy.set_value(&mut self.prune, prefix, value.to_string())?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_SUGGEST_REASONS == opt => {
+                policy.set_flag(&mut self.suggest_reasons, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_TIME_MACHINE == opt => {
+                policy.set_flag(&mut self.time_machine, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_CRITICALITY == opt => {
+                policy.set_flag(&mut self.criticality, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_SESSION_GAP == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                let gap =
+                    value.parse().ok().filter(|gap| *gap >= 0).ok_or_else(|| {
+                        ArgError::InvalidValue(prefix.to_string(), value.to_string())
+                    })?;
+                policy.set_value(&mut self.session_gap, prefix, gap)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_DIFF == opt => {
+                policy.set_flag(&mut self.diff, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_WHY == opt => {
+                policy.set_flag(&mut self.why, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_MAX_FANOUT == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                let top =
+                    value.parse().ok().filter(|top| *top > 0).ok_or_else(|| {
+                        ArgError::InvalidValue(prefix.to_string(), value.to_string())
+                    })?;
+                policy.set_value(&mut self.max_fanout, prefix, top)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_ADJACENCY_JSON == opt => {
+                policy.set_flag(&mut self.adjacency_json, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_MERMAID == opt => {
+                policy.set_flag(&mut self.mermaid, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_DEPENDS == opt => {
+                policy.set_flag(&mut self.depends, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_INCLUDE_DEPS_OF == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                policy.set_value(&mut self.include_deps_of, prefix, value.to_string())?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_DEPTH == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                let depth = value
+                    .parse()
+                    .ok()
+                    .filter(|depth| *depth > 0)
+                    .ok_or_else(|| ArgError::InvalidValue(prefix.to_string(), value.to_string()))?;
+                policy.set_value(&mut self.depth, prefix, depth)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_IDLE_PACKAGES == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                let days = value
+                    .parse()
+                    .ok()
+                    .filter(|days| *days >= 0)
+                    .ok_or_else(|| ArgError::InvalidValue(prefix.to_string(), value.to_string()))?;
+                policy.set_value(&mut self.idle_packages, prefix, days)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_SUBTRACT_FILE == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                policy.set_value(&mut self.subtract_file, prefix, value.to_string())?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_SECTIONS == opt => {
+                policy.set_flag(&mut self.sections, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_CLASSIFY == opt => {
+                policy.set_flag(&mut self.classify, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_HUB_THRESHOLD == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                let threshold = value
+                    .parse()
+                    .map_err(|_| ArgError::InvalidValue(prefix.to_string(), value.to_string()))?;
+                policy.set_value(&mut self.hub_threshold, prefix, threshold)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_REACHES == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                policy.set_value(&mut self.reaches, prefix, value.to_string())?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_TREE == opt => {
+                policy.set_flag(&mut self.tree, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_REQBY_REPO == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                policy.set_value(&mut self.reqby_repo, prefix, value.to_string())?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_SORT == opt => {
+                let (prefix, value) = opt.split_once("=").expect("this has already been verified");
+                policy.set_value(&mut self.sort, prefix, PackageSort::parse(value)?)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_OPTIONAL == opt => {
+                policy.set_flag(&mut self.optional, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_STREAM == opt => {
+                policy.set_flag(&mut self.stream, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_ORPHANS == opt => {
+                policy.set_flag(&mut self.orphans, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_MERGE == opt => {
+                policy.set_flag(&mut self.merge, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            opt if OPT_API_LIST_ROOT_PATHS == opt => {
+                policy.set_flag(&mut self.root_paths, option)?;
+                Ok(Api::List(Box::new(self)))
+            }
+            QUERY_SEPARATOR if self.diff_queries.is_none() => {
+                self.diff_queries = Some(Vec::new());
+                Ok(Api::List(Box::new(self)))
             }
             opt if !is_option(&opt) => {
-                self.queries.push(option);
-                Ok(Api::List(self))
+                match &mut self.diff_queries {
+                    Some(queries) => queries.push(option),
+                    None => self.queries.push(option),
+                }
+                Ok(Api::List(Box::new(self)))
             }
             _ => Err(ArgError::UnknownOption(option)),
         }
@@ -239,29 +1237,79 @@ pub enum Api {
     Empty,
     Help,
     HelpWith(String),
-    List(ApiList),
+    Version,
+    ListFormats,
+    CheckFormat,
+    List(Box<ApiList>),
 }
 
 impl Api {
-    fn add_option(self, opt: String) -> Result<Self, ArgError> {
+    fn add_option(
+        self,
+        opt: String,
+        policy: ConflictPolicy,
+        default_api: Option<&str>,
+    ) -> Result<Self, ArgError> {
+        // wins over any command, no matter where it is given
+        if OPT_VERSION == opt.as_str() {
+            return Ok(Api::Version);
+        }
         match self {
             Api::Help => Ok(self),
+            Api::Version => Ok(self),
             Api::HelpWith(_) => Ok(self),
+            Api::ListFormats => Ok(self),
+            Api::CheckFormat => Ok(self),
             Api::Empty => match opt.as_str() {
-                opt if OPT_API_LIST == opt => Ok(Api::List(ApiList::new())),
+                opt if OPT_API_LIST == opt => Ok(Api::List(Box::new(ApiList::new()))),
                 opt if OPT_HELP == opt => Ok(Api::Help),
-                unknown => Err(ArgError::UnknownOption(unknown.to_string())),
+                opt if OPT_LIST_FORMATS == opt => Ok(Api::ListFormats),
+                opt if OPT_CHECK_FORMAT == opt => Ok(Api::CheckFormat),
+                // options of the default command can be given without naming it
+                unknown => match Api::from_env(default_api)? {
+                    Api::Empty => Err(ArgError::UnknownOption(unknown.to_string())),
+                    api => api.add_option(opt, policy, default_api),
+                },
             },
-            Api::List(list) => list.add_option(opt),
+            Api::List(list) => (*list).add_option(opt, policy),
+        }
+    }
+
+    fn add_query(self, query: String, default_api: Option<&str>) -> Result<Self, ArgError> {
+        match self {
+            Api::Empty => match Api::from_env(default_api)? {
+                Api::Empty => Err(ArgError::UnknownOption(query)),
+                api => api.add_query(query, default_api),
+            },
+            Api::List(mut list) => {
+                match &mut list.diff_queries {
+                    Some(queries) => queries.push(query),
+                    None => list.queries.push(query),
+                }
+                Ok(Api::List(list))
+            }
+            _ => Ok(self),
         }
     }
 
     fn apply_defaults(self) -> Self {
         match self {
-            Api::List(list) => Api::List(list.apply_defaults()),
+            Api::List(list) => Api::List(Box::new((*list).apply_defaults())),
             _ => self,
         }
     }
+
+    // default_api is the value of the environment variable, if set
+    fn from_env(default_api: Option<&str>) -> Result<Self, ArgError> {
+        match default_api {
+            Some("list") => Ok(Api::List(Box::new(ApiList::new()))),
+            Some("") | None => Ok(Api::Empty),
+            Some(other) => Err(ArgError::InvalidValue(
+                ENV_DEFAULT_API.to_string(),
+                other.to_string(),
+            )),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -269,6 +1317,12 @@ pub struct CommonOptions {
     pub verbose: bool,
     pub color: bool,
     pub format: Option<String>,
+    pub xargs_safe: bool,
+    pub benchmark: bool,
+    pub json: bool,
+    pub print0: bool,
+    pub count: bool,
+    pub ignore_case: bool,
 }
 
 impl Default for CommonOptions {
@@ -277,6 +1331,12 @@ impl Default for CommonOptions {
             verbose: false,
             color: false,
             format: None,
+            xargs_safe: false,
+            benchmark: false,
+            json: false,
+            print0: false,
+            count: false,
+            ignore_case: false,
         }
     }
 }
@@ -284,53 +1344,128 @@ impl Default for CommonOptions {
 struct CliOptions {
     pub api: Api,
     pub common: CommonOptions,
+    pub policy: ConflictPolicy,
+    // decided together with --color once every option is known
+    pub no_color: bool,
+    // checked against each other once every option is known
+    pub given: Vec<&'static CliOption>,
+    // the command to use when none is given, from the environment
+    pub default_api: Option<String>,
 }
 
 impl CliOptions {
-    fn new() -> CliOptions {
+    fn new(policy: ConflictPolicy, default_api: Option<&str>) -> CliOptions {
         CliOptions {
             api: Api::Empty,
             common: CommonOptions::default(),
+            policy,
+            no_color: false,
+            given: Vec::new(),
+            default_api: default_api.map(str::to_string),
         }
     }
 
     fn add_option(mut self, option: String) -> Result<Self, ArgError> {
+        if let Some(known) = OPT_LIST.iter().find(|known| **known == option) {
+            self.given.push(known);
+        }
         match option.as_str() {
             opt if OPT_FORMAT == opt => {
                 let (prefix, value) = opt.split_once("=").expect("this has already been verified");
-                if self.common.format.is_none() {
-                    self.common.format = Some(value.to_string());
-                    Ok(self)
-                } else {
-                    Err(ArgError::DuplicateOption(prefix.to_string()))
-                }
+                self.policy
+                    .set_value(&mut self.common.format, prefix, value.to_string())?;
+                Ok(self)
             }
+            // already consumed by find_conflict_policy
+            opt if OPT_ON_CONFLICT == opt => Ok(self),
             opt if OPT_VERBOSE == opt => {
-                if !self.common.verbose {
-                    self.common.verbose = true;
-                    Ok(self)
-                } else {
-                    Err(ArgError::DuplicateOption(option))
-                }
+                self.policy.set_flag(&mut self.common.verbose, option)?;
+                Ok(self)
             }
             opt if OPT_COLOR == opt => {
-                if !self.common.color {
-                    self.common.color = true;
-                    Ok(self)
-                } else {
-                    Err(ArgError::DuplicateOption(option))
-                }
+                self.policy.set_flag(&mut self.common.color, option)?;
+                Ok(self)
+            }
+            opt if OPT_NO_COLOR == opt => {
+                self.policy.set_flag(&mut self.no_color, option)?;
+                Ok(self)
+            }
+            opt if OPT_XARGS_SAFE == opt => {
+                self.policy.set_flag(&mut self.common.xargs_safe, option)?;
+                Ok(self)
+            }
+            opt if OPT_JSON == opt => {
+                self.policy.set_flag(&mut self.common.json, option)?;
+                Ok(self)
+            }
+            opt if OPT_PRINT0 == opt => {
+                self.policy.set_flag(&mut self.common.print0, option)?;
+                Ok(self)
+            }
+            opt if OPT_COUNT == opt => {
+                self.policy.set_flag(&mut self.common.count, option)?;
+                Ok(self)
+            }
+            opt if OPT_BENCHMARK == opt => {
+                self.policy.set_flag(&mut self.common.benchmark, option)?;
+                Ok(self)
+            }
+            opt if OPT_IGNORE_CASE == opt => {
+                self.policy.set_flag(&mut self.common.ignore_case, option)?;
+                Ok(self)
             }
             _ => {
-                self.api = self.api.add_option(option)?;
+                self.api = self
+                    .api
+                    .add_option(option, self.policy, self.default_api.as_deref())?;
                 Ok(self)
             }
         }
     }
 
-    fn apply_defaults(mut self) -> Self {
+    fn add_query(mut self, query: String) -> Result<Self, ArgError> {
+        self.api = self.api.add_query(query, self.default_api.as_deref())?;
+        Ok(self)
+    }
+
+    fn apply_defaults(mut self) -> Result<Self, ArgError> {
+        // the environment is only consulted if no command was given
+        if matches!(self.api, Api::Empty) {
+            self.api = Api::from_env(self.default_api.as_deref())?;
+        }
+        if matches!(self.api, Api::List(_)) {
+            check_compatibility(&self.given)?;
+        }
+        if matches!(self.api, Api::CheckFormat) && self.common.format.is_none() {
+            return Err(ArgError::Needs(
+                OPT_CHECK_FORMAT.long.to_string(),
+                vec![OPT_FORMAT.long.to_string()],
+            ));
+        }
         self.api = self.api.apply_defaults();
-        self
+        self.common.color = color_enabled(
+            self.common.color,
+            self.no_color,
+            io::stdout().is_terminal(),
+            env::var(ENV_NO_COLOR).ok().as_deref(),
+        );
+        Ok(self)
+    }
+}
+
+// --no-color wins over --color, without either colors are used on terminals unless NO_COLOR is set
+fn color_enabled(
+    color: bool,
+    no_color: bool,
+    on_terminal: bool,
+    no_color_env: Option<&str>,
+) -> bool {
+    if no_color {
+        false
+    } else if color {
+        true
+    } else {
+        on_terminal && no_color_env.is_none_or(str::is_empty)
     }
 }
 
@@ -338,6 +1473,11 @@ impl CliOptions {
 pub enum ArgError {
     UnknownOption(String),
     DuplicateOption(String),
+    InvalidValue(String, String),
+    AmbiguousOption(String, Vec<String>),
+    // the option and the ones it works with, one of which is missing
+    Needs(String, Vec<String>),
+    Incompatible(String, String),
 }
 
 impl error::Error for ArgError {}
@@ -348,6 +1488,21 @@ impl fmt::Display for ArgError {
         match self {
             UnknownOption(opt) => write!(f, "unknown option: {}", opt),
             DuplicateOption(opt) => write!(f, "duplicate option: {}", opt),
+            InvalidValue(opt, value) => write!(f, "invalid value for {}: {}", opt, value),
+            AmbiguousOption(opt, candidates) => write!(
+                f,
+                "ambiguous option: {} could be {}",
+                opt,
+                candidates.join(", ")
+            ),
+            Needs(opt, needed) => match needed.split_last() {
+                Some((last, [])) => write!(f, "{} only works with {}", opt, last),
+                Some((last, rest)) => {
+                    write!(f, "{} only works with {} or {}", opt, rest.join(", "), last)
+                }
+                None => write!(f, "{} cannot be used here", opt),
+            },
+            Incompatible(opt, other) => write!(f, "{} cannot be combined with {}", opt, other),
         }
     }
 }
@@ -355,13 +1510,25 @@ impl fmt::Display for ArgError {
 pub fn parse_args<I: IntoIterator<Item = String>>(
     args: I,
 ) -> Result<(Api, CommonOptions), ArgError> {
-    expand_short(split_short(args))
+    parse_args_with_default(args, env::var(ENV_DEFAULT_API).ok().as_deref())
+}
+
+fn parse_args_with_default<I: IntoIterator<Item = String>>(
+    args: I,
+    default_api: Option<&str>,
+) -> Result<(Api, CommonOptions), ArgError> {
+    let (args, literal) = split_end_of_options(args);
+    let options: Vec<_> = expand_short(split_short(args)).into_iter().collect();
+    let policy = find_conflict_policy(&options)?;
+
+    options
         .into_iter()
-        .fold(Ok(CliOptions::new()), |res, opt| {
+        .fold(Ok(CliOptions::new(policy, default_api)), |res, opt| {
             res.and_then(|cli| opt.and_then(|o| cli.add_option(o)))
         })
-        .map(CliOptions::apply_defaults)
-        .map(|CliOptions { api, common }| (api, common))
+        .and_then(|cli| literal.into_iter().try_fold(cli, CliOptions::add_query))
+        .and_then(CliOptions::apply_defaults)
+        .map(|CliOptions { api, common, .. }| (api, common))
 }
 
 pub fn print_argument_group(option: Option<&str>) -> Result<String, ArgError> {
@@ -424,3 +1591,327 @@ pub fn print_argument_group(option: Option<&str>) -> Result<String, ArgError> {
 
     Ok(lines.join("\n"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Api, CommonOptions), ArgError> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_list(args: &[&str]) -> ApiList {
+        match parse(args) {
+            Ok((Api::List(list), _)) => *list,
+            other => panic!("{:?} is not a listing: {:?}", args, other),
+        }
+    }
+
+    #[test]
+    fn diff_groups_are_split_by_the_separator_after_diff() {
+        let list = parse_list(&["-L", "--diff", "vim", "--", "nano"]);
+        assert!(list.diff);
+        assert_eq!(list.queries, ["vim"]);
+        assert_eq!(list.diff_queries, Some(vec!["nano".to_string()]));
+    }
+
+    #[test]
+    fn end_of_options_before_diff_makes_it_a_query() {
+        let list = parse_list(&["-L", "--", "--diff"]);
+        assert!(!list.diff);
+        assert_eq!(list.queries, ["--diff"]);
+        assert_eq!(list.diff_queries, None);
+    }
+
+    fn incompatible(args: &[&str]) -> String {
+        match parse(args) {
+            Err(err @ (ArgError::Needs(..) | ArgError::Incompatible(..))) => err.to_string(),
+            other => panic!("{:?} was accepted: {:?}", args, other),
+        }
+    }
+
+    #[test]
+    fn listing_modes_exclude_each_other() {
+        assert_eq!(
+            incompatible(&["-L", "--required-by", "--criticality", "vim"]),
+            "--required-by cannot be combined with --criticality"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--required-by", "--tree", "--mermaid", "vim"]),
+            "--tree cannot be combined with --mermaid"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--count", "--format=%n"]),
+            "--count cannot be combined with --format"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--reverse-deps-matrix", "--count", "vim"]),
+            "--count cannot be combined with --reverse-deps-matrix"
+        );
+    }
+
+    #[test]
+    fn options_need_their_listing() {
+        assert_eq!(
+            incompatible(&["-L", "--tree", "vim"]),
+            "--tree only works with --required-by"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--required-by", "--tree", "-e", "vim"]),
+            "--tree cannot be combined with --explicit"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--depth=2"]),
+            "--depth only works with --required-by, --reverse-deps-matrix or --depends"
+        );
+    }
+
+    #[test]
+    fn leaves_and_orphans_exclude_listings_of_dependents() {
+        assert_eq!(
+            incompatible(&["-L", "--explicit-leaves", "--criticality"]),
+            "--explicit-leaves cannot be combined with --criticality"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--suggest-reasons", "--orphans"]),
+            "--orphans cannot be combined with --suggest-reasons"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--orphans", "--explicit"]),
+            "--orphans cannot be combined with --explicit"
+        );
+    }
+
+    #[test]
+    fn compatible_options_are_accepted() {
+        parse_list(&["-L", "--required-by", "--merge", "--sort-reqby=name", "vim"]);
+        parse_list(&["-L", "--required-by", "--json", "--optional", "vim"]);
+        parse_list(&["-L", "--orphans", "--idle-packages=30"]);
+    }
+
+    #[test]
+    fn conflict_policy_on_flags_and_values() {
+        let mut flag = false;
+        assert!(ConflictPolicy::Error
+            .set_flag(&mut flag, "-e".to_string())
+            .is_ok());
+        assert!(ConflictPolicy::Error
+            .set_flag(&mut flag, "-e".to_string())
+            .is_err());
+        assert!(ConflictPolicy::Skip
+            .set_flag(&mut flag, "-e".to_string())
+            .is_ok());
+
+        let mut value = None;
+        assert!(ConflictPolicy::Skip
+            .set_value(&mut value, "--depth", 2)
+            .is_ok());
+        assert!(ConflictPolicy::Skip
+            .set_value(&mut value, "--depth", 2)
+            .is_ok());
+        assert!(ConflictPolicy::Skip
+            .set_value(&mut value, "--depth", 3)
+            .is_err());
+        assert!(ConflictPolicy::Error
+            .set_value(&mut value, "--depth", 2)
+            .is_err());
+        assert_eq!(value, Some(2));
+    }
+
+    #[test]
+    fn repeated_flags_fail_unless_skipped() {
+        assert!(matches!(
+            parse(&["-L", "-e", "-e"]),
+            Err(ArgError::DuplicateOption(opt)) if opt == "--explicit"
+        ));
+        assert!(parse_list(&["-L", "--on-conflict=skip", "-e", "-e"]).explicit);
+    }
+
+    #[test]
+    fn repeated_values_only_fail_when_they_differ() {
+        let args = ["-L", "-r", "--sort-reqby=name", "--sort-reqby=name", "vim"];
+        assert!(matches!(parse(&args), Err(ArgError::DuplicateOption(_))));
+        let args = [
+            "--on-conflict=skip",
+            "-L",
+            "-r",
+            "--sort-reqby=name",
+            "--sort-reqby=name",
+        ];
+        assert_eq!(parse_list(&args).sort_reqby, Some(ReqBySort::Name));
+        let args = [
+            "--on-conflict=skip",
+            "-L",
+            "--sort-reqby=name",
+            "--sort-reqby=depth",
+        ];
+        assert!(matches!(parse(&args), Err(ArgError::DuplicateOption(_))));
+    }
+
+    #[test]
+    fn on_conflict_can_be_repeated_with_the_same_policy() {
+        assert!(
+            parse_list(&["--on-conflict=skip", "--on-conflict=skip", "-L"])
+                .queries
+                .is_empty()
+        );
+        assert!(matches!(
+            parse(&["--on-conflict=skip", "--on-conflict=error", "-L"]),
+            Err(ArgError::DuplicateOption(_))
+        ));
+    }
+
+    #[test]
+    fn version_wins_over_any_command() {
+        assert!(matches!(parse(&["-V"]), Ok((Api::Version, _))));
+        assert!(matches!(parse(&["-L", "-e", "-V"]), Ok((Api::Version, _))));
+        assert!(matches!(parse(&["--version", "-h"]), Ok((Api::Version, _))));
+    }
+
+    #[test]
+    fn everything_after_end_of_options_is_a_query() {
+        let list = parse_list(&["-L", "--", "-e", "--required-by"]);
+        assert_eq!(list.queries, ["-e", "--required-by"]);
+        assert!(!list.required_by);
+        // -e wasn't given, so both reasons are listed as usual
+        assert!(list.explicit && list.dependency);
+    }
+
+    #[test]
+    fn expand_long_prefixes() {
+        let expand = |option: &str| expand_long(option.to_string());
+        assert_eq!(expand("--requ").unwrap(), "--required-by");
+        assert_eq!(expand("--form=%n %v").unwrap(), "--format=%n %v");
+        // an exact match wins over the longer options it is a prefix of
+        assert_eq!(expand("--sort=size").unwrap(), "--sort=size");
+        assert_eq!(expand("--merge").unwrap(), "--merge");
+        // unknown options are left for add_option to reject, queries aren't touched
+        assert_eq!(expand("--bogus").unwrap(), "--bogus");
+        assert_eq!(expand("linux").unwrap(), "linux");
+    }
+
+    #[test]
+    fn ambiguous_prefix_lists_the_candidates() {
+        match parse(&["-L", "--re"]) {
+            Err(ArgError::AmbiguousOption(prefix, candidates)) => {
+                assert_eq!(prefix, "--re");
+                assert!(candidates.len() > 1);
+                assert!(candidates.iter().all(|c| c.starts_with("--re")));
+                assert!(candidates.contains(&"--required-by".to_string()));
+            }
+            other => panic!("--re was not ambiguous: {:?}", other),
+        }
+        assert!(parse_list(&["-L", "--requ", "vim"]).required_by);
+    }
+
+    #[test]
+    fn no_color_env_turns_colors_off_on_a_terminal() {
+        assert!(color_enabled(false, false, true, None));
+        assert!(!color_enabled(false, false, true, Some("1")));
+        // an empty NO_COLOR counts as unset
+        assert!(color_enabled(false, false, true, Some("")));
+        assert!(!color_enabled(false, false, false, None));
+    }
+
+    #[test]
+    fn color_options_win_over_the_terminal() {
+        assert!(color_enabled(true, false, false, Some("1")));
+        assert!(!color_enabled(true, true, true, None));
+        assert!(!color_enabled(false, true, true, None));
+    }
+
+    #[test]
+    fn subtracting_needs_a_listing_of_names() {
+        assert_eq!(
+            incompatible(&[
+                "-L",
+                "--required-by",
+                "--tree",
+                "--subtract-file=known",
+                "vim"
+            ]),
+            "--subtract-file cannot be combined with --tree"
+        );
+        assert_eq!(
+            incompatible(&["-L", "--depends", "--subtract-file=known", "vim"]),
+            "--subtract-file cannot be combined with --depends"
+        );
+        let list = parse_list(&["-L", "--required-by", "--subtract-file=known", "vim"]);
+        assert_eq!(list.subtract_file.as_deref(), Some("known"));
+    }
+
+    #[test]
+    fn json_replaces_the_format() {
+        assert_eq!(
+            incompatible(&["-L", "--json", "--format=%n", "vim"]),
+            "--json cannot be combined with --format"
+        );
+    }
+
+    #[test]
+    fn checking_a_format_needs_one() {
+        assert!(matches!(
+            parse(&["--check-format", "--format=%n"]),
+            Ok((Api::CheckFormat, common)) if common.format.as_deref() == Some("%n")
+        ));
+        assert_eq!(
+            incompatible(&["--check-format"]),
+            "--check-format only works with --format"
+        );
+    }
+
+    fn parse_with_default(args: &[&str], default_api: &str) -> Result<Api, ArgError> {
+        parse_args_with_default(args.iter().map(|arg| arg.to_string()), Some(default_api))
+            .map(|(api, _)| api)
+    }
+
+    #[test]
+    fn default_command_only_applies_without_one() {
+        assert!(matches!(parse_with_default(&[], "list"), Ok(Api::List(_))));
+        assert!(matches!(parse_with_default(&[], ""), Ok(Api::Empty)));
+        assert!(matches!(
+            parse_with_default(&["--required-by", "vim"], "list"),
+            Ok(Api::List(list)) if list.required_by && list.queries == ["vim"]
+        ));
+        assert!(matches!(
+            parse_with_default(&["--version"], "list"),
+            Ok(Api::Version)
+        ));
+        assert!(matches!(
+            parse_with_default(&[], "tree"),
+            Err(ArgError::InvalidValue(var, value)) if var == ENV_DEFAULT_API && value == "tree"
+        ));
+        // an explicit command is fine whatever the variable holds
+        assert!(matches!(
+            parse_with_default(&["--list-formats"], "tree"),
+            Ok(Api::ListFormats)
+        ));
+    }
+
+    #[test]
+    fn depends_is_a_listing_of_its_own() {
+        let list = parse_list(&["-L", "--depends", "vim"]);
+        assert!(list.depends && !list.required_by);
+        assert_eq!(list.queries, ["vim"]);
+        assert_eq!(
+            incompatible(&["-L", "--depends", "--required-by", "vim"]),
+            "--depends cannot be combined with --required-by"
+        );
+    }
+
+    #[test]
+    fn packages_sort_by_name_or_size() {
+        assert_eq!(
+            parse_list(&["-L", "--sort=size"]).sort,
+            Some(PackageSort::Size)
+        );
+        assert_eq!(
+            parse_list(&["-L", "--sort=name"]).sort,
+            Some(PackageSort::Name)
+        );
+        assert!(matches!(
+            parse(&["-L", "--sort=date"]),
+            Err(ArgError::InvalidValue(option, value)) if option == "--sort" && value == "date"
+        ));
+    }
+}
@@ -1,9 +1,19 @@
-use super::argparse::{ApiList as ListOptions, CommonOptions};
-use super::output::CompiledFormat;
-use super::query::Query;
+use super::argparse::{ApiList as ListOptions, CommonOptions, PackageSort, ReqBySort};
+use super::output::{
+    json_string, package_json, CompiledFormat, PackageFields, PrintError, Printer, RenderContext,
+    Spinner,
+};
+use super::query::{description_matches, glob_match, read_queries, Query, STDIN_QUERY};
+use super::timing::Timings;
 use super::ProgramError;
-use alpm::{Alpm, Db, PackageReason, Pkg};
-use std::collections::VecDeque;
+use alpm::{Alpm, Db, Package, PackageReason, SigLevel};
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
+use std::convert::Infallible;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum ReasonSelector {
@@ -16,6 +26,36 @@ trait Reason {
     fn is_explicit(&self) -> bool;
 }
 
+trait Orphan {
+    // installed as a dependency, but nothing requires it anymore
+    fn is_orphan(&self) -> bool;
+}
+
+// the lookups the walks make in the local db, so they can also walk packages made up in tests
+trait PackageDb {
+    type Package: PackageFields;
+    fn pkg(&self, name: &str) -> Option<&Self::Package>;
+    // the package satisfying a dependency such as glibc>=2.38, which may be one providing it
+    fn satisfier(&self, depend: &str) -> Option<&Self::Package>;
+    fn pkgs(&self) -> Vec<&Self::Package>;
+}
+
+impl PackageDb for Db {
+    type Package = Package;
+
+    fn pkg(&self, name: &str) -> Option<&Package> {
+        Db::pkg(self, name).ok()
+    }
+
+    fn satisfier(&self, depend: &str) -> Option<&Package> {
+        Db::pkgs(self).find_satisfier(depend)
+    }
+
+    fn pkgs(&self) -> Vec<&Package> {
+        Db::pkgs(self).into_iter().collect()
+    }
+}
+
 impl ReasonSelector {
     fn filter(self, reason: PackageReason) -> Option<PackageReason> {
         match (self, reason) {
@@ -39,62 +79,920 @@ impl ReasonSelector {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum ReqByItem {
-    Explicit(String),
-    Depend(String),
+struct ReqByItem<'a, P = Package> {
+    pkg: &'a P,
+    reason: PackageReason,
+    // number of packages directly requiring this one
+    dependents: usize,
+    // direct dependents of the queried package are at depth 1
+    depth: usize,
+    // found through an optional dependency somewhere along the way
+    optional: bool,
 }
 
-impl Reason for ReqByItem {
+impl<P> Reason for ReqByItem<'_, P> {
     fn is_explicit(&self) -> bool {
-        matches!(self, ReqByItem::Explicit(_))
+        self.reason == PackageReason::Explicit
     }
 }
 
-impl ReqByItem {
-    fn draw(self, color: bool) -> String {
-        match self {
-            ReqByItem::Explicit(name) if color => format!("\x1b[33m{}\x1b[m", name),
-            ReqByItem::Explicit(name) | ReqByItem::Depend(name) => format!("{}", name),
+impl<'a, P: PackageFields> ReqByItem<'a, P> {
+    fn name(&self) -> &'a str {
+        self.pkg.name()
+    }
+
+    fn draw(
+        self,
+        format: &CompiledFormat,
+        context: &RenderContext<P>,
+        color: bool,
+        counts: bool,
+        root_path: bool,
+    ) -> String {
+        let mut label = format.display_at_depth(self.pkg, self.depth, context);
+        if counts {
+            label = format!("{}({})", label, self.dependents);
+        }
+        if root_path {
+            label = format!("{} (root-path)", label);
+        }
+
+        // a reset inside the label would end the color of the whole label early
+        let color = color && !format.uses_color();
+        match self.reason {
+            _ if self.optional && color => format!("\x1b[36m{}\x1b[m", label),
+            PackageReason::Explicit if color => format!("\x1b[33m{}\x1b[m", label),
+            _ => label,
         }
     }
 }
 
-fn find_required_by(db: &Db, pkg: &Pkg, reason_filter: ReasonSelector) -> Vec<ReqByItem> {
-    let mut queue: VecDeque<&Pkg> = [pkg].into();
-    let mut required_by: Vec<ReqByItem> = Vec::new();
+// explicit packages in the same yellow as the explicit dependents of --required-by, dependencies
+// are left alone like there
+fn color_by_reason(line: String, reason: PackageReason, color: bool) -> String {
+    match reason {
+        PackageReason::Explicit if color => format!("\x1b[33m{}\x1b[m", line),
+        _ => line,
+    }
+}
+
+struct WalkOptions<'o> {
+    transitive: bool,
+    // these packages are neither listed nor walked through
+    prune: Vec<&'o str>,
+    max_depth: Option<usize>,
+    // also walk to the packages that only optionally depend on a package
+    optional: bool,
+}
 
-    while !queue.is_empty() {
-        let next = queue.pop_front().unwrap();
-        let reqby = next.required_by();
+impl WalkOptions<'_> {
+    // whether the packages found at this depth are walked in turn
+    fn walks_from(&self, depth: usize) -> bool {
+        self.transitive && self.max_depth.is_none_or(|max| depth < max)
+    }
+}
+
+fn find_required_by<'a, D: PackageDb>(
+    db: &'a D,
+    pkg: &D::Package,
+    reason_filter: ReasonSelector,
+    walk: &WalkOptions,
+) -> Vec<ReqByItem<'a, D::Package>> {
+    let mut required_by: Vec<ReqByItem<D::Package>> = Vec::new();
+    let Ok(()) = walk_required_by::<_, Infallible>(db, pkg, walk, |item| {
+        required_by.push(item);
+        Ok(())
+    });
+
+    // filtering only after the walk means -e still finds explicit packages that depend on the
+    // query through dependency packages, filtering during the walk would stop at those
+    required_by
+        .into_iter()
+        .filter(|r| reason_filter.test(r))
+        .collect()
+}
 
-        for name in reqby.iter().map(|s| s.to_string()) {
-            let Ok(pkg) = db.pkg(name.clone()) else {
+// hands every dependent to found as soon as it is discovered, stopping at the first error
+fn walk_required_by<'a, D: PackageDb, E>(
+    db: &'a D,
+    pkg: &D::Package,
+    walk: &WalkOptions,
+    mut found: impl FnMut(ReqByItem<'a, D::Package>) -> Result<(), E>,
+) -> Result<(), E> {
+    walk_dependents(
+        dependents_of(pkg, walk.optional),
+        walk,
+        |name| {
+            let pkg = db.pkg(name);
+            if pkg.is_none() {
                 eprintln!("failed to fetch info for {}", name);
+            }
+            pkg
+        },
+        |pkg| dependents_of(*pkg, walk.optional),
+        |pkg: &&'a D::Package, depth, optional| {
+            found(ReqByItem {
+                pkg,
+                reason: pkg.reason(),
+                dependents: pkg.required_by().len(),
+                depth,
+                optional,
+            })
+        },
+    )
+}
+
+// the dependents of every package, each listed once where it was first found
+fn find_required_by_merged<'a, D: PackageDb>(
+    db: &'a D,
+    pkgs: &[&D::Package],
+    reason_filter: ReasonSelector,
+    walk: &WalkOptions,
+) -> Vec<ReqByItem<'a, D::Package>> {
+    let mut seen = HashSet::new();
+    let mut required_by = Vec::new();
+    for pkg in pkgs {
+        required_by.extend(
+            find_required_by(db, *pkg, reason_filter, walk)
+                .into_iter()
+                .filter(|r| seen.insert(r.name())),
+        );
+    }
+    required_by
+}
+
+// package names, each paired with whether it only optionally depends on the package at hand
+type Dependents = Vec<(String, bool)>;
+
+// the packages requiring pkg, with optional also those it is an optional dependency of
+fn dependents_of(pkg: &impl PackageFields, optional: bool) -> Dependents {
+    let mut dependents: Vec<_> = pkg
+        .required_by()
+        .into_iter()
+        .map(|name| (name, false))
+        .collect();
+    if optional {
+        dependents.extend(pkg.optional_for().into_iter().map(|name| (name, true)));
+    }
+    dependents
+}
+
+// the breadth first walk of walk_required_by over any graph, starting from the dependents of the
+// query. lookup leaves out the names it returns None for
+fn walk_dependents<N, E>(
+    start: Dependents,
+    walk: &WalkOptions,
+    lookup: impl Fn(&str) -> Option<N>,
+    dependents: impl Fn(&N) -> Dependents,
+    mut found: impl FnMut(&N, usize, bool) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut queue: VecDeque<(Dependents, usize, bool)> = [(start, 0, false)].into();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    while let Some((reqby, depth, through_optional)) = queue.pop_front() {
+        for (name, optional) in reqby {
+            if walk.prune.contains(&name.as_str()) {
+                continue;
+            }
+
+            let Some(node) = lookup(&name) else {
                 continue;
             };
 
-            let reason = pkg.reason();
-            let req = match reason {
-                PackageReason::Explicit => ReqByItem::Explicit(name),
-                PackageReason::Depend => ReqByItem::Depend(name),
+            if !seen.insert(name) {
+                continue;
+            }
+
+            let optional = through_optional || optional;
+            found(&node, depth + 1, optional)?;
+            if walk.walks_from(depth + 1) {
+                queue.push_back((dependents(&node), depth + 1, optional));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// the shortest chain from pkg up to its nearest explicit dependent, both ends included
+fn find_why_chain<'a, D: PackageDb>(
+    db: &'a D,
+    pkg: &'a D::Package,
+    walk: &WalkOptions,
+) -> Option<Vec<&'a D::Package>> {
+    // every package found so far, along with the index of the package it requires
+    let mut found: Vec<(&D::Package, usize)> = vec![(pkg, 0)];
+    let mut seen: HashSet<String> = [pkg.name().to_string()].into();
+    let mut queue: VecDeque<usize> = [0].into();
+
+    while let Some(i) = queue.pop_front() {
+        let (next, _) = found[i];
+        if next.reason() == PackageReason::Explicit {
+            let mut chain = vec![next];
+            let mut j = i;
+            while j != 0 {
+                j = found[j].1;
+                chain.push(found[j].0);
+            }
+            chain.reverse();
+            return Some(chain);
+        }
+
+        for name in next.required_by() {
+            if walk.prune.contains(&name.as_str()) || !seen.insert(name.clone()) {
+                continue;
+            }
+            let Some(pkg) = db.pkg(&name) else {
+                eprintln!("failed to fetch info for {}", name);
+                continue;
             };
+            found.push((pkg, i));
+            queue.push_back(found.len() - 1);
+        }
+    }
+
+    None
+}
+
+// the shortest chain of dependencies from pkg down to target, both ends included. the walk stops
+// as soon as target is found
+fn find_path<'a, D: PackageDb>(
+    db: &'a D,
+    pkg: &'a D::Package,
+    target: &str,
+    walk: &WalkOptions,
+) -> Option<Vec<&'a D::Package>> {
+    // every package found so far, along with the index of the package depending on it and depth
+    let mut found: Vec<(&D::Package, usize, usize)> = vec![(pkg, 0, 0)];
+    let mut queue: VecDeque<usize> = [0].into();
+
+    while let Some(i) = queue.pop_front() {
+        let (next, _, depth) = found[i];
+        if next.name() == target {
+            let mut path = vec![next];
+            let mut j = i;
+            while j != 0 {
+                j = found[j].1;
+                path.push(found[j].0);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if i != 0 && !walk.walks_from(depth) {
+            continue;
+        }
 
-            if required_by.contains(&req) {
+        for dep in next.depends() {
+            let Some(dep_pkg) = db.satisfier(&dep) else {
+                eprintln!("failed to find a package satisfying {}", dep);
+                continue;
+            };
+            if walk.prune.contains(&dep_pkg.name())
+                || found.iter().any(|(p, _, _)| p.name() == dep_pkg.name())
+            {
                 continue;
             }
+            found.push((dep_pkg, i, depth + 1));
+            queue.push_back(found.len() - 1);
+        }
+    }
 
-            required_by.push(req);
-            queue.push_back(pkg);
+    None
+}
+
+// the queried packages and everything walked from them, each listed once. the reason filter is
+// not applied as it would leave holes in the graph
+fn required_by_nodes<'a, D: PackageDb>(
+    db: &'a D,
+    pkgs: &[&'a D::Package],
+    walk: &WalkOptions,
+) -> Vec<&'a D::Package> {
+    let mut nodes: Vec<&D::Package> = Vec::new();
+    for pkg in pkgs {
+        let found = find_required_by(db, pkg, ReasonSelector::Both, walk);
+        for node in [*pkg].into_iter().chain(found.into_iter().map(|r| r.pkg)) {
+            if nodes.iter().all(|n| n.name() != node.name()) {
+                nodes.push(node);
+            }
         }
     }
+    nodes
+}
 
-    required_by
+// every walked package mapped to its direct dependents among the walked packages
+fn render_adjacency_json<D: PackageDb>(db: &D, pkgs: &[&D::Package], walk: &WalkOptions) -> String {
+    let nodes = required_by_nodes(db, pkgs, walk);
+
+    let entries: Vec<_> = nodes
+        .iter()
+        .map(|node| {
+            let dependents: Vec<_> = node
+                .required_by()
+                .iter()
+                .filter(|name| nodes.iter().any(|n| n.name() == *name))
+                .map(|name| json_string(name))
+                .collect();
+            format!("{}:[{}]", json_string(node.name()), dependents.join(","))
+        })
+        .collect();
+
+    format!("{{{}}}", entries.join(","))
+}
+
+// draws the dependents of a package below it, one level of indentation per step. with
+// --optional, a package that only optionally depends on the one above it is marked
+struct TreeDrawing<'t, 'a, D: PackageDb> {
+    db: &'a D,
+    walk: &'t WalkOptions<'t>,
+    display: &'t dyn Fn(&D::Package) -> String,
+    // a package is only expanded the first time it is drawn, the tree of glibc would never end
+    drawn: HashSet<String>,
+    ancestors: Vec<String>,
+    lines: Vec<String>,
+}
+
+impl<'t, 'a, D: PackageDb> TreeDrawing<'t, 'a, D> {
+    fn draw(
+        db: &'a D,
+        pkg: &D::Package,
+        walk: &'t WalkOptions<'t>,
+        display: &'t dyn Fn(&D::Package) -> String,
+    ) -> Vec<String> {
+        let mut drawing = TreeDrawing {
+            db,
+            walk,
+            display,
+            drawn: [pkg.name().to_string()].into(),
+            ancestors: vec![pkg.name().to_string()],
+            lines: vec![display(pkg)],
+        };
+        drawing.draw_dependents(pkg, 0, "");
+        drawing.lines
+    }
+
+    fn draw_dependents(&mut self, pkg: &D::Package, depth: usize, indent: &str) {
+        let dependents: Vec<_> = dependents_of(pkg, self.walk.optional)
+            .into_iter()
+            .filter(|(name, _)| !self.walk.prune.contains(&name.as_str()))
+            .filter_map(|(name, optional)| match self.db.pkg(&name) {
+                Some(pkg) => Some((pkg, optional)),
+                None => {
+                    eprintln!("failed to fetch info for {}", name);
+                    None
+                }
+            })
+            .collect();
+
+        for (i, (dependent, optional)) in dependents.iter().enumerate() {
+            let (connector, continuation) = if i + 1 == dependents.len() {
+                ("└─ ", "   ")
+            } else {
+                ("├─ ", "│  ")
+            };
+            let name = dependent.name().to_string();
+            let mut line = format!("{}{}{}", indent, connector, (self.display)(dependent));
+            if *optional {
+                line.push_str(" (optional)");
+            }
+
+            if self.ancestors.contains(&name) {
+                self.lines.push(format!("{} (cycle)", line));
+            } else if !self.drawn.insert(name.clone()) {
+                self.lines.push(format!("{} (see above)", line));
+            } else {
+                self.lines.push(line);
+                if self.walk.walks_from(depth + 1) {
+                    self.ancestors.push(name);
+                    let indent = format!("{}{}", indent, continuation);
+                    self.draw_dependents(dependent, depth + 1, &indent);
+                    self.ancestors.pop();
+                }
+            }
+        }
+    }
+}
+
+// a mermaid flowchart with an arrow from each package to its dependents. package names are not
+// valid node ids, so the nodes are numbered and labelled with the name
+fn render_mermaid<D: PackageDb>(db: &D, pkgs: &[&D::Package], walk: &WalkOptions) -> Vec<String> {
+    let nodes = required_by_nodes(db, pkgs, walk);
+    let id = |name: &str| nodes.iter().position(|n| n.name() == name);
+
+    let mut lines = vec!["graph TD".to_string()];
+    lines.extend(
+        nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| format!("    n{}[\"{}\"]", i, node.name())),
+    );
+    for (i, node) in nodes.iter().enumerate() {
+        lines.extend(
+            node.required_by()
+                .iter()
+                .filter_map(|name| id(name))
+                .map(|j| format!("    n{} --> n{}", i, j)),
+        );
+    }
+
+    let explicit: Vec<_> = nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| node.reason() == PackageReason::Explicit)
+        .map(|(i, _)| format!("n{}", i))
+        .collect();
+    if !explicit.is_empty() {
+        lines.push("    classDef explicit font-weight:bold,stroke-width:3px".to_string());
+        lines.push(format!("    class {} explicit", explicit.join(",")));
+    }
+    lines
+}
+
+pub fn count_required_by(db: &Db, pkg: &Package) -> usize {
+    count_dependents(db, pkg)
+}
+
+fn count_dependents<D: PackageDb>(db: &D, pkg: &D::Package) -> usize {
+    let walk = WalkOptions {
+        transitive: true,
+        prune: Vec::new(),
+        max_depth: None,
+        optional: false,
+    };
+    find_required_by(db, pkg, ReasonSelector::Both, &walk).len()
+}
+
+// with a header (--sections) the dependents are indented below it. otherwise separate puts every
+// dependent in its own entry, as xargs -0 doesn't split on spaces and --xargs-safe wouldn't allow
+// them, and without it they share one line
+fn required_by_entries(header: Option<String>, reqby: Vec<String>, separate: bool) -> Vec<String> {
+    match header {
+        Some(header) => [header]
+            .into_iter()
+            .chain(reqby.into_iter().map(|line| format!("  {}", line)))
+            .collect(),
+        None if separate => reqby,
+        None if reqby.is_empty() => Vec::new(),
+        None => vec![reqby.join(" ")],
+    }
+}
+
+// the same walk as find_required_by, following the dependencies instead
+fn find_depends<'a, D: PackageDb>(
+    db: &'a D,
+    pkg: &D::Package,
+    reason_filter: ReasonSelector,
+    walk: &WalkOptions,
+) -> Vec<ReqByItem<'a, D::Package>> {
+    let mut queue: VecDeque<(&D::Package, usize)> = [(pkg, 0)].into();
+    let mut depends: Vec<ReqByItem<D::Package>> = Vec::new();
+    // a dependency cycle can lead back to the package we started from
+    let mut seen: HashSet<String> = [pkg.name().to_string()].into();
+
+    while let Some((next, depth)) = queue.pop_front() {
+        for dep in next.depends() {
+            let Some(dep_pkg) = db.satisfier(&dep) else {
+                eprintln!("failed to find a package satisfying {}", dep);
+                continue;
+            };
+
+            if walk.prune.contains(&dep_pkg.name()) || !seen.insert(dep_pkg.name().to_string()) {
+                continue;
+            }
+
+            depends.push(ReqByItem {
+                pkg: dep_pkg,
+                reason: dep_pkg.reason(),
+                dependents: dep_pkg.required_by().len(),
+                depth: depth + 1,
+                optional: false,
+            });
+            if walk.walks_from(depth + 1) {
+                queue.push_back((dep_pkg, depth + 1));
+            }
+        }
+    }
+
+    depends
         .into_iter()
-        .filter(|r| reason_filter.test(r))
+        .filter(|d| reason_filter.test(d))
+        .collect()
+}
+
+pub fn all_depends<'a>(db: &'a Db, pkg: &Package) -> Vec<&'a Package> {
+    transitive_depends(db, pkg)
+}
+
+fn transitive_depends<'a, D: PackageDb>(db: &'a D, pkg: &D::Package) -> Vec<&'a D::Package> {
+    let walk = WalkOptions {
+        transitive: true,
+        prune: Vec::new(),
+        max_depth: None,
+        optional: false,
+    };
+    find_depends(db, pkg, ReasonSelector::Both, &walk)
+        .into_iter()
+        .map(|d| d.pkg)
+        .collect()
+}
+
+// pkg and everything it depends on, after the packages already there
+fn add_footprint<'a, D: PackageDb>(db: &'a D, pkgs: &mut Vec<&'a D::Package>, pkg: &'a D::Package) {
+    for dep in [pkg].into_iter().chain(transitive_depends(db, pkg)) {
+        if pkgs.iter().all(|p| p.name() != dep.name()) {
+            pkgs.push(dep);
+        }
+    }
+}
+
+fn sort_packages<P: PackageFields>(pkgs: &mut [&P], sort: PackageSort) {
+    pkgs.sort_by(|a, b| {
+        let order = match sort {
+            PackageSort::Name => Ordering::Equal,
+            PackageSort::Size => b.isize().cmp(&a.isize()),
+        };
+        order.then_with(|| a.name().cmp(b.name()))
+    });
+}
+
+fn sort_required_by<P: PackageFields>(items: &mut [ReqByItem<P>], sort: ReqBySort) {
+    items.sort_by(|a, b| {
+        let order = match sort {
+            ReqBySort::Name => Ordering::Equal,
+            ReqBySort::Reason => a.reason.cmp(&b.reason),
+            ReqBySort::Depth => a.depth.cmp(&b.depth),
+        };
+        order.then_with(|| a.name().cmp(b.name()))
+    });
+}
+
+// an explicit package something depends on could be a dependency, and a dependency nothing
+// depends on is either unneeded or wanted explicitly
+fn suggest_reason(pkg: &impl PackageFields) -> Option<String> {
+    let required_by = pkg.required_by();
+    match pkg.reason() {
+        PackageReason::Explicit if !required_by.is_empty() => Some(format!(
+            "{}: Explicit -> Depend, required by {}",
+            pkg.name(),
+            required_by.join(", ")
+        )),
+        PackageReason::Depend if required_by.is_empty() => Some(format!(
+            "{}: Depend -> Explicit, nothing requires it",
+            pkg.name()
+        )),
+        _ => None,
+    }
+}
+
+// every transitive dependent counts once, and explicit ones count again since they are what the
+// user actually asked for
+fn criticality_score<D: PackageDb>(db: &D, pkg: &D::Package, walk: &WalkOptions) -> usize {
+    let dependents = find_required_by(db, pkg, ReasonSelector::Both, walk);
+    let explicit = dependents.iter().filter(|r| r.is_explicit()).count();
+    dependents.len() + explicit
+}
+
+fn render_criticality<D: PackageDb>(
+    db: &D,
+    pkgs: &[&D::Package],
+    walk: &WalkOptions,
+    format: &CompiledFormat,
+    context: &RenderContext<D::Package>,
+) -> Vec<String> {
+    let mut scored: Vec<_> = pkgs
+        .iter()
+        .map(|pkg| (criticality_score(db, pkg, walk), *pkg))
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    scored
+        .into_iter()
+        .map(|(score, pkg)| format!("{} {}", score, format.display(pkg, context)))
+        .collect()
+}
+
+fn render_max_fanout<D: PackageDb>(
+    db: &D,
+    pkgs: &[&D::Package],
+    top: usize,
+    format: &CompiledFormat,
+    context: &RenderContext<D::Package>,
+) -> Vec<String> {
+    let mut counted: Vec<_> = pkgs
+        .iter()
+        .map(|pkg| (count_dependents(db, *pkg), *pkg))
+        .collect();
+    counted.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    counted
+        .into_iter()
+        .take(top)
+        .map(|(count, pkg)| format!("{} {}", count, format.display(pkg, context)))
+        .collect()
+}
+
+// like diff, packages only in before are prefixed with - and those only in after with +
+fn render_diff<P: PackageFields>(
+    before: &[&P],
+    after: &[&P],
+    format: &CompiledFormat,
+    context: &RenderContext<P>,
+) -> Vec<String> {
+    let missing_from = |pkgs: &[&P], pkg: &P| pkgs.iter().all(|p| p.name() != pkg.name());
+    let removed = before
+        .iter()
+        .filter(|pkg| missing_from(after, pkg))
+        .map(|pkg| format!("- {}", format.display(*pkg, context)));
+    let added = after
+        .iter()
+        .filter(|pkg| missing_from(before, pkg))
+        .map(|pkg| format!("+ {}", format.display(*pkg, context)));
+
+    removed.chain(added).collect()
+}
+
+// the last time any file of the package was read, otherwise when it was installed. this is only
+// a hint, noatime mounts never update the access time and relatime about once a day
+fn last_used(root: &str, pkg: &Package) -> Option<i64> {
+    let files = pkg.files();
+    files
+        .files()
+        .iter()
+        .filter(|file| !file.name().ends_with('/'))
+        .filter_map(|file| fs::metadata(Path::new(root).join(file.name())).ok())
+        .filter_map(|metadata| metadata.accessed().ok())
+        .filter_map(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs() as i64)
+        .max()
+        .or(pkg.install_date())
+}
+
+// every package that is explicit or (transitively) required by an explicit package. the set grows
+// until nothing changes, which also settles dependency cycles
+fn root_path_packages(db: &impl PackageDb) -> HashSet<String> {
+    let mut on_path: HashSet<String> = HashSet::new();
+    loop {
+        let before = on_path.len();
+        for pkg in db.pkgs() {
+            if !on_path.contains(pkg.name())
+                && (pkg.reason() == PackageReason::Explicit
+                    || pkg.required_by().iter().any(|name| on_path.contains(name)))
+            {
+                on_path.insert(pkg.name().to_string());
+            }
+        }
+        if on_path.len() == before {
+            return on_path;
+        }
+    }
+}
+
+impl<P: PackageFields> Orphan for P {
+    fn is_orphan(&self) -> bool {
+        ReasonSelector::Depend.filter(self.reason()).is_some() && self.required_by().is_empty()
+    }
+}
+
+// explicit_leaves only keeps explicit packages nothing requires, orphans only dependencies nothing
+// requires. the reason filter is set to match either
+fn is_listed<P: PackageFields>(
+    pkg: &P,
+    filter: ReasonSelector,
+    explicit_leaves: bool,
+    orphans: bool,
+) -> bool {
+    filter.filter(pkg.reason()).is_some()
+        && (!explicit_leaves || pkg.required_by().is_empty())
+        && (!orphans || pkg.is_orphan())
+}
+
+fn summarize_reasons<P: PackageFields>(pkgs: &[&P]) -> String {
+    let explicit = pkgs
+        .iter()
+        .filter(|pkg| pkg.reason() == PackageReason::Explicit)
+        .count();
+    format!("explicit: {}, depend: {}", explicit, pkgs.len() - explicit)
+}
+
+// an orphaned dependency that wasn't used within max_idle seconds before now, last_used is only
+// asked for orphans as it is slow
+fn is_idle<P, F>(pkg: &P, last_used: F, now: i64, max_idle: i64) -> bool
+where
+    P: Orphan,
+    F: Fn(&P) -> Option<i64>,
+{
+    pkg.is_orphan() && last_used(pkg).is_some_and(|used| now - used > max_idle)
+}
+
+const DEFAULT_HUB_THRESHOLD: usize = 5;
+
+// by the number of packages directly requiring it
+fn classify_package(pkg: &impl PackageFields, hub_threshold: usize) -> &'static str {
+    match pkg.required_by().len() {
+        0 => "leaf",
+        n if n <= hub_threshold => "branch",
+        _ => "hub",
+    }
+}
+
+const DEFAULT_SESSION_GAP: i64 = 600;
+
+// packages installed at most gap seconds after the previous one belong to the same session,
+// packages without an install date are left out
+fn install_sessions<P, F>(pkgs: &[P], install_date: F, gap: i64) -> Vec<Vec<P>>
+where
+    P: Copy,
+    F: Fn(P) -> Option<i64>,
+{
+    let mut dated: Vec<_> = pkgs
+        .iter()
+        .filter_map(|pkg| install_date(*pkg).map(|date| (date, *pkg)))
+        .collect();
+    dated.sort_by_key(|(date, _)| *date);
+
+    let mut sessions: Vec<Vec<P>> = Vec::new();
+    let mut last_date = None;
+    for (date, pkg) in dated {
+        match (sessions.last_mut(), last_date) {
+            (Some(session), Some(last)) if date - last <= gap => session.push(pkg),
+            _ => sessions.push(vec![pkg]),
+        }
+        last_date = Some(date);
+    }
+
+    sessions
+}
+
+fn render_install_sessions(
+    sessions: &[Vec<&Package>],
+    format: &CompiledFormat,
+    context: &RenderContext<Package>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (i, session) in sessions.iter().enumerate() {
+        lines.push(format!("session {} ({} packages)", i + 1, session.len()));
+        for pkg in session {
+            lines.push(format!("  {}", format.display(*pkg, context)));
+        }
+    }
+
+    lines
+}
+
+fn render_group_members<P: PackageFields>(
+    pkgs: &[&P],
+    format: &CompiledFormat,
+    context: &RenderContext<P>,
+) -> Vec<String> {
+    let mut groups: Vec<&str> = Vec::new();
+    for group in pkgs.iter().flat_map(|pkg| pkg.groups()) {
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+
+    let mut lines = Vec::new();
+    for group in groups {
+        lines.push(group.to_string());
+        for pkg in pkgs.iter().filter(|pkg| pkg.groups().contains(&group)) {
+            lines.push(format!("  {}", format.display(*pkg, context)));
+        }
+    }
+
+    lines
+}
+
+fn render_reverse_deps_matrix(
+    db: &Db,
+    pkgs: &[&Package],
+    reason_filter: ReasonSelector,
+    walk: &WalkOptions,
+) -> Vec<String> {
+    let queries: Vec<&str> = pkgs.iter().map(|pkg| pkg.name()).collect();
+    let columns: Vec<Vec<&str>> = pkgs
+        .iter()
+        .map(|pkg| {
+            find_required_by(db, pkg, reason_filter, walk)
+                .iter()
+                .map(ReqByItem::name)
+                .collect()
+        })
+        .collect();
+    reverse_deps_matrix(&queries, &columns)
+}
+
+// a column of the dependents of each query, a row for every dependent in order of appearance
+fn reverse_deps_matrix(queries: &[&str], columns: &[Vec<&str>]) -> Vec<String> {
+    let mut rows: Vec<&str> = Vec::new();
+    for name in columns.iter().flatten() {
+        if !rows.contains(name) {
+            rows.push(name);
+        }
+    }
+
+    let header = ["package"]
+        .iter()
+        .chain(queries)
+        .copied()
+        .collect::<Vec<_>>()
+        .join(",");
+
+    [header]
+        .into_iter()
+        .chain(rows.into_iter().map(|row| {
+            let cells: Vec<_> = columns
+                .iter()
+                .map(|column| if column.contains(&row) { "1" } else { "0" })
+                .collect();
+            format!("{},{}", row, cells.join(","))
+        }))
         .collect()
 }
 
+// the names of a --subtract-file, one per line like queries from stdin
+fn read_subtracted<R: BufRead>(reader: R) -> io::Result<HashSet<String>> {
+    Ok(read_queries(reader)?.into_iter().collect())
+}
+
+// overlapping queries, like foo and f*, would otherwise list a package twice. the first match
+// decides where it is listed
+fn dedup_by_name<P: PackageFields>(pkgs: &mut Vec<&P>) {
+    let mut seen: HashSet<String> = HashSet::new();
+    pkgs.retain(|pkg| seen.insert(pkg.name().to_string()));
+}
+
+const FOREIGN_REPO: &str = "foreign";
+
+// pacman.conf isn't read, every repo pacman has synced is registered under its file name
+fn register_sync_dbs(handle: &Alpm) -> io::Result<()> {
+    for entry in fs::read_dir(Path::new(handle.dbpath()).join("sync"))? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "db") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if handle.register_syncdb(name, SigLevel::USE_DEFAULT).is_err() {
+            eprintln!("failed to register the {} repo", name);
+        }
+    }
+    Ok(())
+}
+
+// the first synced repo that has the package, like pacman -Si would pick
+fn package_repo<'h>(handle: &'h Alpm, name: &str) -> &'h str {
+    handle
+        .syncdbs()
+        .into_iter()
+        .find(|db| db.pkg(name).is_ok())
+        .map_or(FOREIGN_REPO, |db| db.name())
+}
+
+// every package is in without a wanted repo. the repo is only looked up otherwise, as that
+// searches every sync db
+fn in_wanted_repo<'r>(wanted: Option<&str>, repo: impl FnOnce() -> &'r str) -> bool {
+    wanted.is_none_or(|wanted| repo() == wanted)
+}
+
+// the names that only differ from name in case, for when no package has exactly that name
+fn names_ignoring_case<'n>(name: &str, names: impl IntoIterator<Item = &'n str>) -> Vec<&'n str> {
+    names
+        .into_iter()
+        .filter(|n| n.eq_ignore_ascii_case(name))
+        .collect()
+}
+
+// an exact lookup is tried first, ignore_case only scans the whole db if it fails
+fn resolve_query<D: PackageDb>(db: &D, query: Query, ignore_case: bool) -> Vec<&D::Package> {
+    let pkgs = || db.pkgs().into_iter();
+    match query {
+        Query::PackageName(name) => match db.pkg(&name) {
+            Some(pkg) => vec![pkg],
+            None if ignore_case => names_ignoring_case(&name, pkgs().map(|pkg| pkg.name()))
+                .into_iter()
+                .filter_map(|name| db.pkg(name))
+                .collect(),
+            None => Vec::new(),
+        },
+        Query::Glob(pattern) => pkgs()
+            .filter(|pkg| glob_match(&pattern, pkg.name()))
+            .collect(),
+        Query::Description(pattern) => pkgs()
+            .filter(|pkg| {
+                pkg.desc()
+                    .is_some_and(|desc| description_matches(&pattern, desc))
+            })
+            .collect(),
+        Query::ProvidesCount(comparison) => pkgs()
+            .filter(|pkg| comparison.test(pkg.provides_count()))
+            .collect(),
+        Query::Reason(reason) => pkgs().filter(|pkg| pkg.reason() == reason).collect(),
+        Query::Group(group) => pkgs()
+            .filter(|pkg| pkg.groups().contains(&group.as_str()))
+            .collect(),
+    }
+}
+
 pub fn list_packages(
     handle: Alpm,
     ListOptions {
@@ -102,13 +1000,58 @@ pub fn list_packages(
         explicit,
         dependency,
         required_by,
+        reverse_deps_matrix,
+        reason_summary,
+        explicit_leaves,
+        no_transitive,
+        reqby_counts,
+        sort_reqby,
+        group_members,
+        prune,
+        suggest_reasons,
+        time_machine,
+        session_gap,
+        criticality,
+        diff,
+        why,
+        max_fanout,
+        adjacency_json,
+        mermaid,
+        depends,
+        include_deps_of,
+        depth,
+        idle_packages,
+        subtract_file,
+        sections,
+        classify,
+        hub_threshold,
+        reaches,
+        tree,
+        reqby_repo,
+        sort,
+        optional,
+        stream,
+        orphans,
+        merge,
+        root_paths,
+        diff_queries,
     }: ListOptions,
-    CommonOptions { color, format, .. }: CommonOptions,
+    CommonOptions {
+        verbose,
+        color,
+        format,
+        xargs_safe,
+        benchmark: _,
+        json,
+        print0,
+        count,
+        ignore_case,
+    }: CommonOptions,
+    timings: &mut Timings,
 ) -> Result<(), ProgramError> {
     let compiled_format = match &format {
-        Some(f) => {
-            CompiledFormat::compile(f.as_str()).ok_or(ProgramError::InvalidFormat(f.clone()))?
-        }
+        Some(f) => CompiledFormat::compile(f.as_str())
+            .map_err(|err| ProgramError::InvalidFormat(f.clone(), err))?,
         None => CompiledFormat::default(),
     };
 
@@ -120,48 +1063,1393 @@ pub fn list_packages(
         ));
     }
 
-    let filter = ReasonSelector::new(explicit, dependency);
+    if number_queries == 0 && depends {
+        return Err(ProgramError::InvalidRequest(
+            "you cannot use --depends without specifying packages".to_string(),
+        ));
+    }
+
+    if number_queries == 0 && reverse_deps_matrix {
+        return Err(ProgramError::InvalidRequest(
+            "you cannot use --reverse-deps-matrix without specifying packages".to_string(),
+        ));
+    }
+
+    if diff && (number_queries == 0 || diff_queries.as_ref().is_none_or(Vec::is_empty)) {
+        return Err(ProgramError::InvalidRequest(
+            "--diff needs two groups of packages separated by --".to_string(),
+        ));
+    }
+
+    if !diff && diff_queries.is_some() {
+        return Err(ProgramError::InvalidRequest(
+            "-- only works with --diff".to_string(),
+        ));
+    }
+
+    if verbose && max_fanout.is_some() {
+        eprintln!("walking the dependents of every listed package, this may take a while");
+    }
+
+    if verbose && (required_by || reverse_deps_matrix) {
+        if no_transitive {
+            eprintln!("showing direct dependents only");
+        } else {
+            eprintln!("showing all transitive dependents, use --no-transitive for direct ones");
+        }
+    }
+
+    let walk = WalkOptions {
+        transitive: !no_transitive,
+        prune: prune
+            .as_deref()
+            .map(|p| p.split(",").collect())
+            .unwrap_or_default(),
+        max_depth: depth,
+        optional,
+    };
+
+    let filter = if explicit_leaves {
+        ReasonSelector::Explicit
+    } else if orphans {
+        ReasonSelector::Depend
+    } else {
+        ReasonSelector::new(explicit, dependency)
+    };
+
+    let mut expanded = Vec::new();
+    for query in queries {
+        if query == STDIN_QUERY {
+            expanded.extend(read_queries(io::stdin().lock()).map_err(ProgramError::Input)?);
+        } else {
+            expanded.push(query);
+        }
+    }
 
-    let queries: Vec<Query> = queries
+    let queries: Vec<Query> = expanded
         .into_iter()
         .map(|s| Query::parse(&s))
         .collect::<Result<_, _>>()?;
 
+    // left out of the results, but still walked through
+    let subtracted: HashSet<String> = match subtract_file {
+        Some(path) => {
+            let file = File::open(path).map_err(ProgramError::Input)?;
+            read_subtracted(BufReader::new(file)).map_err(ProgramError::Input)?
+        }
+        None => HashSet::new(),
+    };
+
+    if reqby_repo.is_some() || compiled_format.needs_sync_dbs() {
+        register_sync_dbs(&handle).map_err(ProgramError::Input)?;
+    }
+    let in_repo =
+        |pkg: &Package| in_wanted_repo(reqby_repo.as_deref(), || package_repo(&handle, pkg.name()));
+
     let local = handle.localdb();
 
-    let pkgs: Vec<_> = if queries.is_empty() {
+    let mut pkgs: Vec<_> = if number_queries == 0 && include_deps_of.is_none() {
         local.pkgs().into_iter().collect()
     } else {
         queries
             .into_iter()
-            .filter_map(|q| match q {
-                Query::PackageName(name) => local.pkg(name).ok(),
-            })
+            .flat_map(|q| resolve_query(local, q, ignore_case))
             .collect()
     };
+    dedup_by_name(&mut pkgs);
+
+    // the footprint of the package, each package listed once even with dependency cycles
+    if let Some(name) = include_deps_of {
+        let Ok(pkg) = local.pkg(name.as_str()) else {
+            return Err(ProgramError::InvalidRequest(format!(
+                "{} is not installed",
+                name
+            )));
+        };
+        add_footprint(local, &mut pkgs, pkg);
+    }
+
+    if let Some(sort) = sort {
+        sort_packages(&mut pkgs, sort);
+    }
 
     if pkgs.is_empty() {
         return Err(ProgramError::NoPackagesFound);
     }
 
-    let mut lines: Vec<String> = Vec::new();
-    for pkg in pkgs.into_iter() {
-        if required_by {
-            let reqby: Vec<_> = find_required_by(local, pkg, filter)
+    // the plain listing and both groups of --diff narrow the packages down the same way
+    let listed = |pkg: &Package| {
+        is_listed(pkg, filter, explicit_leaves, orphans) && !subtracted.contains(pkg.name())
+    };
+
+    let mut diff_pkgs: Vec<_> = diff_queries
+        .unwrap_or_default()
+        .into_iter()
+        .map(|s| Query::parse(&s))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flat_map(|q| resolve_query(local, q, ignore_case))
+        .filter(|pkg| listed(pkg))
+        .collect();
+    dedup_by_name(&mut diff_pkgs);
+    timings.lap("query resolution");
+
+    let root_path = root_paths.then(|| root_path_packages(local));
+    let on_root_path = |r: &ReqByItem| {
+        root_path
+            .as_ref()
+            .is_some_and(|on_path| on_path.contains(r.name()))
+    };
+
+    // --xargs-safe must see every line before anything is printed
+    let mut printer = if count {
+        Printer::counting(print0)
+    } else {
+        Printer::new(!xargs_safe, print0, xargs_safe)
+    };
+    if reverse_deps_matrix {
+        let lines = render_reverse_deps_matrix(local, &pkgs, filter, &walk);
+        timings.lap("traversal");
+        for line in lines {
+            printer.push(line)?;
+        }
+    } else if required_by && why {
+        for pkg in pkgs.into_iter() {
+            let chain = find_why_chain(local, pkg, &walk);
+            timings.lap("traversal");
+            let Some(chain) = chain else {
+                if verbose {
+                    eprintln!("nothing explicitly installed requires {}", pkg.name());
+                }
+                continue;
+            };
+            let context = RenderContext::new(&handle, &chain, color);
+            let chain: Vec<_> = chain
+                .iter()
+                .map(|p| compiled_format.display(*p, &context))
+                .collect();
+            printer.push(chain.join(" <- "))?;
+            timings.lap("rendering");
+        }
+    } else if depends && count {
+        // a dependency of several of the queried packages is counted once
+        let mut seen = HashSet::new();
+        for pkg in pkgs.into_iter() {
+            for d in find_depends(local, pkg, filter, &walk) {
+                if seen.insert(d.name()) {
+                    printer.push(d.name().to_string())?;
+                }
+            }
+            timings.lap("traversal");
+        }
+    } else if depends {
+        for pkg in pkgs.into_iter() {
+            let depends = find_depends(local, pkg, filter, &walk);
+            timings.lap("traversal");
+            let dependencies: Vec<_> = depends.iter().map(|d| d.pkg).collect();
+            let context = RenderContext::new(&handle, &dependencies, color);
+            let depends: Vec<_> = depends
                 .into_iter()
-                .map(|r| r.draw(color))
+                .map(|d| d.draw(&compiled_format, &context, color, false, false))
                 .collect();
-            if !reqby.is_empty() {
-                lines.push(reqby.join(" "));
+            if print0 || xargs_safe {
+                for line in depends {
+                    printer.push(line)?;
+                }
+            } else if !depends.is_empty() {
+                printer.push(depends.join(" "))?;
+            }
+            timings.lap("rendering");
+        }
+    } else if required_by && adjacency_json {
+        let adjacency = render_adjacency_json(local, &pkgs, &walk);
+        timings.lap("traversal");
+        printer.push(adjacency)?;
+    } else if required_by && mermaid {
+        let lines = render_mermaid(local, &pkgs, &walk);
+        timings.lap("traversal");
+        for line in lines.into_iter() {
+            printer.push(line)?;
+        }
+    } else if required_by && json {
+        let mut objects = Vec::new();
+        for pkg in pkgs.into_iter() {
+            let mut reqby = find_required_by(local, pkg, filter, &walk);
+            reqby.retain(|r| !subtracted.contains(r.name()) && in_repo(r.pkg));
+            if let Some(sort) = sort_reqby {
+                sort_required_by(&mut reqby, sort);
+            }
+            timings.lap("traversal");
+            let dependents: Vec<_> = reqby.iter().map(|r| r.pkg).collect();
+            objects.push(package_json(pkg, Some(&dependents)));
+        }
+        printer.push(format!("[{}]", objects.join(",")))?;
+    } else if required_by && count {
+        // a package requiring several of the queried packages is counted once
+        let mut seen = HashSet::new();
+        for pkg in pkgs.into_iter() {
+            for r in find_required_by(local, pkg, filter, &walk) {
+                if !subtracted.contains(r.name()) && in_repo(r.pkg) && seen.insert(r.name()) {
+                    printer.push(r.name().to_string())?;
+                }
+            }
+            timings.lap("traversal");
+        }
+    } else if required_by && stream {
+        // the dependents still to be found are unknown, so tokens comparing packages see none
+        let context = RenderContext::new(&handle, &[], color);
+        let mut spinner = Spinner::on_terminal();
+        for pkg in pkgs.into_iter() {
+            walk_required_by(local, pkg, &walk, |r| {
+                spinner.clear();
+                if filter.test(&r) && !subtracted.contains(r.name()) && in_repo(r.pkg) {
+                    let marked = on_root_path(&r);
+                    printer.push(r.draw(
+                        &compiled_format,
+                        &context,
+                        color,
+                        reqby_counts,
+                        marked,
+                    ))?;
+                    printer.flush()?;
+                }
+                spinner.tick();
+                Ok::<(), PrintError>(())
+            })?;
+        }
+        spinner.clear();
+        timings.lap("traversal");
+    } else if required_by && tree {
+        for pkg in pkgs.into_iter() {
+            let nodes = required_by_nodes(local, &[pkg], &walk);
+            let context = RenderContext::new(&handle, &nodes, color);
+            let display = |p: &Package| compiled_format.display(p, &context);
+            let lines = TreeDrawing::draw(local, pkg, &walk, &display);
+            timings.lap("traversal");
+            for line in lines.into_iter() {
+                printer.push(line)?;
+            }
+        }
+    } else if required_by && merge {
+        let mut reqby = find_required_by_merged(local, &pkgs, filter, &walk);
+        reqby.retain(|r| !subtracted.contains(r.name()) && in_repo(r.pkg));
+        if let Some(sort) = sort_reqby {
+            sort_required_by(&mut reqby, sort);
+        }
+        timings.lap("traversal");
+        let dependents: Vec<_> = reqby.iter().map(|r| r.pkg).collect();
+        let context = RenderContext::new(&handle, &dependents, color);
+        let reqby: Vec<_> = reqby
+            .into_iter()
+            .map(|r| {
+                let marked = on_root_path(&r);
+                r.draw(&compiled_format, &context, color, reqby_counts, marked)
+            })
+            .collect();
+        for entry in required_by_entries(None, reqby, print0 || xargs_safe) {
+            printer.push(entry)?;
+        }
+        timings.lap("rendering");
+    } else if required_by {
+        for pkg in pkgs.into_iter() {
+            let mut reqby = find_required_by(local, pkg, filter, &walk);
+            reqby.retain(|r| !subtracted.contains(r.name()) && in_repo(r.pkg));
+            if let Some(sort) = sort_reqby {
+                sort_required_by(&mut reqby, sort);
+            }
+            timings.lap("traversal");
+            let dependents: Vec<_> = reqby.iter().map(|r| r.pkg).collect();
+            let context = RenderContext::new(&handle, &dependents, color);
+            let reqby: Vec<_> = reqby
+                .into_iter()
+                .map(|r| {
+                    let marked = on_root_path(&r);
+                    r.draw(&compiled_format, &context, color, reqby_counts, marked)
+                })
+                .collect();
+            let header = sections.then(|| compiled_format.display(pkg, &context));
+            for entry in required_by_entries(header, reqby, print0 || xargs_safe) {
+                printer.push(entry)?;
+            }
+            timings.lap("rendering");
+        }
+    } else {
+        // the context needs the whole matched set, so filter before rendering
+        let matched: Vec<_> = pkgs.into_iter().filter(|pkg| listed(pkg)).collect();
+        if json {
+            let objects: Vec<_> = matched.iter().map(|pkg| package_json(*pkg, None)).collect();
+            printer.push(format!("[{}]", objects.join(",")))?;
+        } else if reason_summary {
+            printer.push(summarize_reasons(&matched))?;
+        } else if suggest_reasons {
+            for line in matched.into_iter().filter_map(suggest_reason) {
+                printer.push(line)?;
+            }
+        } else if diff {
+            let both: Vec<_> = matched.iter().chain(diff_pkgs.iter()).copied().collect();
+            let context = RenderContext::new(&handle, &both, color);
+            for line in render_diff(&matched, &diff_pkgs, &compiled_format, &context) {
+                printer.push(line)?;
+            }
+        } else if let Some(top) = max_fanout {
+            let context = RenderContext::new(&handle, &matched, color);
+            let lines = render_max_fanout(local, &matched, top, &compiled_format, &context);
+            timings.lap("traversal");
+            for line in lines {
+                printer.push(line)?;
+            }
+        } else if criticality {
+            let context = RenderContext::new(&handle, &matched, color);
+            let lines = render_criticality(local, &matched, &walk, &compiled_format, &context);
+            timings.lap("traversal");
+            for line in lines {
+                printer.push(line)?;
+            }
+        } else if let Some(days) = idle_packages {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64);
+            let idle: Vec<_> = matched
+                .into_iter()
+                .filter(|pkg| {
+                    is_idle(
+                        *pkg,
+                        |p| last_used(handle.root(), p),
+                        now,
+                        days.saturating_mul(86400),
+                    )
+                })
+                .collect();
+            timings.lap("traversal");
+            let context = RenderContext::new(&handle, &idle, color);
+            for pkg in idle.iter() {
+                printer.push(compiled_format.display(*pkg, &context))?;
+            }
+        } else if let Some(target) = reaches {
+            if local.pkg(target.as_str()).is_err() {
+                return Err(ProgramError::InvalidRequest(format!(
+                    "{} is not installed",
+                    target
+                )));
+            }
+            for pkg in matched.into_iter() {
+                let path = find_path(local, pkg, &target, &walk);
+                timings.lap("traversal");
+                match path {
+                    Some(path) => {
+                        let names: Vec<_> = path.into_iter().map(|p| p.name()).collect();
+                        printer.push(format!("yes: {}", names.join(" -> ")))?;
+                    }
+                    None => {
+                        printer.push(format!("no: {} does not depend on {}", pkg.name(), target))?
+                    }
+                }
+            }
+        } else if classify {
+            let threshold = hub_threshold.unwrap_or(DEFAULT_HUB_THRESHOLD);
+            for pkg in matched.into_iter() {
+                printer.push(format!(
+                    "{}: {}",
+                    pkg.name(),
+                    classify_package(pkg, threshold)
+                ))?;
+            }
+        } else if time_machine {
+            let context = RenderContext::new(&handle, &matched, color);
+            let sessions = install_sessions(
+                &matched,
+                |pkg| pkg.install_date(),
+                session_gap.unwrap_or(DEFAULT_SESSION_GAP),
+            );
+            for line in render_install_sessions(&sessions, &compiled_format, &context) {
+                printer.push(line)?;
+            }
+        } else if group_members {
+            let context = RenderContext::new(&handle, &matched, color);
+            for line in render_group_members(&matched, &compiled_format, &context) {
+                printer.push(line)?;
+            }
+        } else {
+            let context = RenderContext::new(&handle, &matched, color);
+            // a reset inside the line would end the color of the whole line early
+            let color_lines = color && !compiled_format.uses_color();
+            for pkg in matched.iter() {
+                let line = compiled_format.display(*pkg, &context);
+                printer.push(color_by_reason(line, pkg.reason(), color_lines))?;
             }
-        } else if filter.filter(pkg.reason()).is_some() {
-            lines.push(compiled_format.display(pkg));
         }
     }
 
-    if !lines.is_empty() {
-        println!("{}", lines.join("\n"));
-    }
+    printer.finish()?;
+    timings.lap("rendering");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::testing::{TestLookup, TestPackage};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    // packages made up for the walks, each only knows the names of its neighbours
+    struct TestDb(Vec<TestPackage>);
+
+    impl PackageDb for TestDb {
+        type Package = TestPackage;
+
+        fn pkg(&self, name: &str) -> Option<&TestPackage> {
+            self.0.iter().find(|pkg| pkg.name == name)
+        }
+
+        // by name, whatever the version constraint
+        fn satisfier(&self, depend: &str) -> Option<&TestPackage> {
+            let name = depend.split(['<', '>', '=']).next().unwrap_or(depend);
+            self.pkg(name)
+        }
+
+        fn pkgs(&self) -> Vec<&TestPackage> {
+            self.0.iter().collect()
+        }
+    }
+
+    fn names<P: PackageFields>(pkgs: &[&P]) -> Vec<String> {
+        pkgs.iter().map(|pkg| pkg.name().to_string()).collect()
+    }
+
+    fn item(pkg: &TestPackage, depth: usize) -> ReqByItem<'_, TestPackage> {
+        ReqByItem {
+            pkg,
+            reason: pkg.reason,
+            dependents: pkg.required_by.len(),
+            depth,
+            optional: false,
+        }
+    }
+
+    #[test]
+    fn matrix_marks_a_shared_dependent_in_both_columns() {
+        let columns = vec![vec!["shared", "only-a"], vec!["shared"]];
+        assert_eq!(
+            reverse_deps_matrix(&["a", "b"], &columns),
+            ["package,a,b", "shared,1,1", "only-a,1,0"]
+        );
+    }
+
+    const TRANSITIVE: WalkOptions = WalkOptions {
+        transitive: true,
+        prune: Vec::new(),
+        max_depth: None,
+        optional: false,
+    };
+
+    // every name of the graph maps to the names of the packages requiring it, and optional to
+    // those of the packages only optionally depending on it
+    fn walk_marked(
+        graph: &HashMap<String, Vec<String>>,
+        optional: &HashMap<String, Vec<String>>,
+        query: &str,
+        walk: &WalkOptions,
+    ) -> Vec<(String, usize, bool)> {
+        let dependents = |name: &str| -> Dependents {
+            let required = graph[name].iter().map(|r| (r.clone(), false));
+            let optional = optional
+                .get(name)
+                .into_iter()
+                .flatten()
+                .filter(|_| walk.optional)
+                .map(|r| (r.clone(), true));
+            required.chain(optional).collect()
+        };
+        let mut found = Vec::new();
+        let Ok(()) = walk_dependents::<_, Infallible>(
+            dependents(query),
+            walk,
+            |name| graph.contains_key(name).then(|| name.to_string()),
+            |name| dependents(name),
+            |name, depth, optional| {
+                found.push((name.clone(), depth, optional));
+                Ok(())
+            },
+        );
+        found
+    }
+
+    fn walk_graph(
+        graph: &HashMap<String, Vec<String>>,
+        query: &str,
+        walk: &WalkOptions,
+    ) -> Vec<(String, usize)> {
+        walk_marked(graph, &HashMap::new(), query, walk)
+            .into_iter()
+            .map(|(name, depth, _)| (name, depth))
+            .collect()
+    }
+
+    fn graph(edges: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        let mut graph: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, required_by) in edges {
+            for dependent in *required_by {
+                graph.entry(dependent.to_string()).or_default();
+            }
+            graph.insert(
+                name.to_string(),
+                required_by.iter().map(|r| r.to_string()).collect(),
+            );
+        }
+        graph
+    }
+
+    fn graph_optional(edges: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        edges
+            .iter()
+            .map(|(name, optional_for)| {
+                let names = optional_for.iter().map(|r| r.to_string()).collect();
+                (name.to_string(), names)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dependents_are_found_in_discovery_order() {
+        let graph = graph(&[("lib", &["b", "c"]), ("b", &["d"]), ("c", &["d", "e"])]);
+        let found = walk_graph(&graph, "lib", &TRANSITIVE);
+        assert_eq!(
+            found,
+            [("b", 1), ("c", 1), ("d", 2), ("e", 2)].map(|(name, depth)| (name.to_string(), depth))
+        );
+    }
+
+    struct Found {
+        name: String,
+        explicit: bool,
+    }
+
+    impl Reason for Found {
+        fn is_explicit(&self) -> bool {
+            self.explicit
+        }
+    }
+
+    // app only requires lib through dep, which is installed as a dependency
+    #[test]
+    fn explicit_dependents_are_found_through_dependencies() {
+        let graph = graph(&[("lib", &["dep", "tool"]), ("dep", &["app"])]);
+        let explicit = ["tool", "app"];
+
+        let reported: Vec<_> = walk_graph(&graph, "lib", &TRANSITIVE)
+            .into_iter()
+            .map(|(name, _)| Found {
+                explicit: explicit.contains(&name.as_str()),
+                name,
+            })
+            .filter(|found| ReasonSelector::Explicit.test(found))
+            .map(|found| found.name)
+            .collect();
+        assert_eq!(reported, ["tool", "app"]);
+
+        // leaving dependencies out of the walk itself never reaches app
+        let mut pruned = graph.clone();
+        pruned.retain(|name, _| name == "lib" || explicit.contains(&name.as_str()));
+        let walked: Vec<_> = walk_graph(&pruned, "lib", &TRANSITIVE)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(walked, ["tool"]);
+    }
+
+    // every one of the many direct dependents is also required by each of a few shared ones,
+    // which have to be listed once after them
+    #[test]
+    fn wide_graphs_list_every_dependent_once() {
+        let direct: Vec<String> = (0..2000).map(|i| format!("direct-{}", i)).collect();
+        let shared: Vec<String> = (0..10).map(|i| format!("shared-{}", i)).collect();
+        let mut graph: HashMap<String, Vec<String>> = HashMap::new();
+        graph.insert("lib".to_string(), direct.clone());
+        for name in &direct {
+            graph.insert(name.clone(), shared.clone());
+        }
+        for name in &shared {
+            graph.insert(name.clone(), Vec::new());
+        }
+
+        let found = walk_graph(&graph, "lib", &TRANSITIVE);
+        let expected: Vec<_> = direct
+            .into_iter()
+            .map(|name| (name, 1))
+            .chain(shared.into_iter().map(|name| (name, 2)))
+            .collect();
+        assert_eq!(found, expected);
+    }
+
+    struct Installed {
+        orphan: bool,
+        last_used: Option<i64>,
+    }
+
+    impl Orphan for Installed {
+        fn is_orphan(&self) -> bool {
+            self.orphan
+        }
+    }
+
+    #[test]
+    fn idle_packages_are_orphans_unused_for_long() {
+        let now = 100 * 86400;
+        let max_idle = 30 * 86400;
+        let is_idle = |orphan, last_used| {
+            let asked = Cell::new(false);
+            let idle = is_idle(
+                &Installed { orphan, last_used },
+                |pkg: &Installed| {
+                    asked.set(true);
+                    pkg.last_used
+                },
+                now,
+                max_idle,
+            );
+            (idle, asked.get())
+        };
+
+        assert_eq!(is_idle(true, Some(now - max_idle - 1)), (true, true));
+        assert_eq!(is_idle(true, Some(now - max_idle)), (false, true));
+        assert_eq!(is_idle(true, None), (false, true));
+        // only orphans are looked up
+        assert_eq!(is_idle(false, Some(0)), (false, false));
+    }
+
+    #[test]
+    fn sessions_split_on_gaps_larger_than_the_window() {
+        let dates = [
+            ("late", Some(5000)),
+            ("first", Some(1000)),
+            ("undated", None),
+            ("second", Some(1600)),
+            ("third", Some(1700)),
+        ];
+        let date_of = |name: &str| dates.iter().find(|(n, _)| *n == name).unwrap().1;
+        let names: Vec<_> = dates.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            install_sessions(&names, date_of, 600),
+            [vec!["first", "second", "third"], vec!["late"]]
+        );
+        assert_eq!(install_sessions(&names, date_of, 599).len(), 3);
+    }
+
+    #[test]
+    fn subtracted_names_are_left_out() {
+        let subtracted = read_subtracted("vim\n\ngvim\n".as_bytes()).unwrap();
+        let mut names = vec!["vim", "neovim", "gvim", "nano"];
+        names.retain(|name| !subtracted.contains(*name));
+        assert_eq!(names, ["neovim", "nano"]);
+    }
+
+    #[test]
+    fn only_explicit_packages_are_colored() {
+        assert_eq!(
+            color_by_reason("vim".to_string(), PackageReason::Explicit, true),
+            "\x1b[33mvim\x1b[m"
+        );
+        assert_eq!(
+            color_by_reason("vim".to_string(), PackageReason::Explicit, false),
+            "vim"
+        );
+        assert_eq!(
+            color_by_reason("libx11".to_string(), PackageReason::Depend, true),
+            "libx11"
+        );
+    }
+
+    // lib <- dep <- app, where app is the first explicit package above lib
+    #[test]
+    fn why_chains_end_at_the_nearest_explicit_dependent() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["dep", "other"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage {
+                required_by: vec!["app"],
+                ..TestPackage::depend("dep")
+            },
+            TestPackage {
+                required_by: vec!["lib"],
+                ..TestPackage::depend("other")
+            },
+            TestPackage::new("app"),
+        ]);
+        let lib = db.pkg("lib").unwrap();
+        let chain = find_why_chain(&db, lib, &TRANSITIVE).unwrap();
+        assert_eq!(names(&chain), ["lib", "dep", "app"]);
+
+        let app = db.pkg("app").unwrap();
+        assert_eq!(
+            names(&find_why_chain(&db, app, &TRANSITIVE).unwrap()),
+            ["app"]
+        );
+
+        // other leads back to lib, which is never walked twice
+        let walk = WalkOptions {
+            prune: vec!["dep"],
+            ..TRANSITIVE
+        };
+        assert!(find_why_chain(&db, lib, &walk).is_none());
+    }
+
+    // app -> lib, tool -> lib>=2, and lib leads back to app
+    #[test]
+    fn dependencies_are_listed_once_through_cycles() {
+        let db = TestDb(vec![
+            TestPackage {
+                depends: vec!["lib", "tool"],
+                ..TestPackage::new("app")
+            },
+            TestPackage {
+                depends: vec!["lib>=2"],
+                ..TestPackage::depend("tool")
+            },
+            TestPackage {
+                depends: vec!["app"],
+                ..TestPackage::depend("lib")
+            },
+        ]);
+        let app = db.pkg("app").unwrap();
+        let found: Vec<_> = find_depends(&db, app, ReasonSelector::Both, &TRANSITIVE)
+            .into_iter()
+            .map(|d| (d.name(), d.depth))
+            .collect();
+        assert_eq!(found, [("lib", 1), ("tool", 1)]);
+    }
+
+    #[test]
+    fn pruned_packages_hide_their_dependents() {
+        let graph = graph(&[("lib", &["b", "c"]), ("b", &["d"]), ("c", &["e"])]);
+        let walk = WalkOptions {
+            prune: vec!["b"],
+            ..TRANSITIVE
+        };
+        let found = walk_graph(&graph, "lib", &walk);
+        assert_eq!(
+            found,
+            [("c", 1), ("e", 2)].map(|(name, depth)| (name.to_string(), depth))
+        );
+    }
+
+    #[test]
+    fn depth_limits_how_far_dependents_are_walked() {
+        let graph = graph(&[("lib", &["b"]), ("b", &["c"]), ("c", &["d"])]);
+        let depths = |max_depth| {
+            let walk = WalkOptions {
+                max_depth,
+                ..TRANSITIVE
+            };
+            walk_graph(&graph, "lib", &walk)
+                .into_iter()
+                .map(|(_, depth)| depth)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(depths(Some(1)), [1]);
+        assert_eq!(depths(Some(2)), [1, 2]);
+        assert_eq!(depths(None), [1, 2, 3]);
+    }
+
+    #[test]
+    fn no_transitive_only_finds_direct_dependents() {
+        let graph = graph(&[("lib", &["b", "c"]), ("b", &["d"]), ("c", &["d", "e"])]);
+        let walk = WalkOptions {
+            transitive: false,
+            ..TRANSITIVE
+        };
+        let found = walk_graph(&graph, "lib", &walk);
+        assert_eq!(
+            found,
+            [("b", 1), ("c", 1)].map(|(name, depth)| (name.to_string(), depth))
+        );
+    }
+
+    // plugin only optionally depends on lib, so it and what requires it are marked
+    #[test]
+    fn optional_dependents_are_only_walked_with_optional() {
+        let graph = graph(&[("lib", &["app"]), ("plugin", &["suite"]), ("app", &[])]);
+        let optional = graph_optional(&[("lib", &["plugin"])]);
+        let walk = WalkOptions {
+            optional: true,
+            ..TRANSITIVE
+        };
+        assert_eq!(
+            walk_marked(&graph, &optional, "lib", &walk),
+            [("app", 1, false), ("plugin", 1, true), ("suite", 2, true)]
+                .map(|(name, depth, optional)| (name.to_string(), depth, optional))
+        );
+        assert_eq!(
+            walk_marked(&graph, &optional, "lib", &TRANSITIVE),
+            [("app".to_string(), 1, false)]
+        );
+    }
+
+    // equal sizes fall back to the name
+    #[test]
+    fn sizes_sort_largest_first() {
+        let sized = |name, isize| TestPackage {
+            isize,
+            ..TestPackage::new(name)
+        };
+        let (vim, nano, emacs) = (sized("vim", 90), sized("nano", 10), sized("emacs", 10));
+        let mut pkgs = vec![&nano, &emacs, &vim];
+        sort_packages(&mut pkgs, PackageSort::Size);
+        assert_eq!(names(&pkgs), ["vim", "emacs", "nano"]);
+        sort_packages(&mut pkgs, PackageSort::Name);
+        assert_eq!(names(&pkgs), ["emacs", "nano", "vim"]);
+    }
+
+    #[test]
+    fn dependents_sort_by_key_then_name() {
+        let (vim, gvim, gpm) = (
+            TestPackage::new("vim"),
+            TestPackage::new("gvim"),
+            TestPackage::depend("gpm"),
+        );
+        let mut items = vec![item(&vim, 1), item(&gpm, 2), item(&gvim, 2)];
+        for (sort, expected) in [
+            (ReqBySort::Name, ["gpm", "gvim", "vim"]),
+            (ReqBySort::Depth, ["vim", "gpm", "gvim"]),
+            (ReqBySort::Reason, ["gvim", "vim", "gpm"]),
+        ] {
+            sort_required_by(&mut items, sort);
+            let sorted: Vec<_> = items.iter().map(ReqByItem::name).collect();
+            assert_eq!(sorted, expected, "{:?}", sort);
+        }
+    }
+
+    // c is reached through both a and b but only expanded below a, where it leads back to a
+    #[test]
+    fn trees_mark_repeated_packages_and_cycles() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["a", "b"],
+                optional_for: vec!["p"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage {
+                required_by: vec!["c"],
+                ..TestPackage::depend("a")
+            },
+            TestPackage {
+                required_by: vec!["c"],
+                ..TestPackage::depend("b")
+            },
+            TestPackage {
+                required_by: vec!["a"],
+                ..TestPackage::new("c")
+            },
+            TestPackage::new("p"),
+        ]);
+        let walk = WalkOptions {
+            optional: true,
+            ..TRANSITIVE
+        };
+        let display = |pkg: &TestPackage| pkg.name.to_string();
+        let lib = db.pkg("lib").unwrap();
+        assert_eq!(
+            TreeDrawing::draw(&db, lib, &walk, &display).join("\n"),
+            "lib\n\
+             ├─ a\n\
+             │  └─ c\n\
+             │     └─ a (cycle)\n\
+             ├─ b\n\
+             │  └─ c (see above)\n\
+             └─ p (optional)"
+        );
+    }
+
+    // app requires both queries and is listed once, at the depth it was first found at
+    #[test]
+    fn merged_dependents_list_a_shared_one_once() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["x", "app"],
+                ..TestPackage::depend("lib1")
+            },
+            TestPackage {
+                required_by: vec!["app", "y"],
+                ..TestPackage::depend("lib2")
+            },
+            TestPackage {
+                required_by: vec!["app"],
+                ..TestPackage::depend("x")
+            },
+            TestPackage::new("app"),
+            TestPackage::new("y"),
+        ]);
+        let queries = [db.pkg("lib1").unwrap(), db.pkg("lib2").unwrap()];
+        let found: Vec<_> =
+            find_required_by_merged(&db, &queries, ReasonSelector::Both, &TRANSITIVE)
+                .into_iter()
+                .map(|r| (r.name(), r.depth))
+                .collect();
+        assert_eq!(found, [("x", 1), ("app", 1), ("y", 1)]);
+    }
+
+    // base only reaches app through lib, which comes after it; a and b only require each other
+    #[test]
+    fn root_paths_lead_up_to_explicit_packages() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["lib"],
+                ..TestPackage::depend("base")
+            },
+            TestPackage {
+                required_by: vec!["app"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage::new("app"),
+            TestPackage::depend("orphan"),
+            TestPackage {
+                required_by: vec!["b"],
+                ..TestPackage::depend("a")
+            },
+            TestPackage {
+                required_by: vec!["a"],
+                ..TestPackage::depend("b")
+            },
+        ]);
+        let mut on_path: Vec<_> = root_path_packages(&db).into_iter().collect();
+        on_path.sort();
+        assert_eq!(on_path, ["app", "base", "lib"]);
+    }
+
+    #[test]
+    fn names_differing_in_case_only_match_with_ignore_case() {
+        assert_eq!(
+            names_ignoring_case("Firefox", ["firefox", "firefox-i18n", "FIREFOX"]),
+            ["firefox", "FIREFOX"]
+        );
+        let db = TestDb(vec![
+            TestPackage::new("firefox"),
+            TestPackage::new("Firefox-dev"),
+            TestPackage::new("firefox-dev"),
+        ]);
+        let resolve = |name: &str, ignore_case| {
+            let query = Query::PackageName(name.to_string());
+            names(&resolve_query(&db, query, ignore_case))
+        };
+        assert!(resolve("Firefox", false).is_empty());
+        assert_eq!(resolve("Firefox", true), ["firefox"]);
+        // an exact match wins
+        assert_eq!(resolve("Firefox-dev", true), ["Firefox-dev"]);
+    }
+
+    // lib is required by app directly and by tool through dep, an explicit dependent counts twice
+    #[test]
+    fn criticality_counts_explicit_dependents_twice() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["app", "dep"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage {
+                required_by: vec!["tool"],
+                ..TestPackage::depend("dep")
+            },
+            TestPackage::new("app"),
+            TestPackage::new("tool"),
+        ]);
+        let pkgs = [
+            db.pkg("dep").unwrap(),
+            db.pkg("lib").unwrap(),
+            db.pkg("app").unwrap(),
+        ];
+        let lookup = TestLookup::default();
+        let context = RenderContext::new(&lookup, &pkgs, false);
+        let format = CompiledFormat::compile("%n").unwrap();
+        assert_eq!(
+            render_criticality(&db, &pkgs, &TRANSITIVE, &format, &context),
+            ["5 lib", "2 dep", "0 app"]
+        );
+    }
+
+    // dep counts tool, which requires it, and lib counts it again through dep
+    #[test]
+    fn max_fanout_keeps_the_most_required_packages() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["app", "dep"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage {
+                required_by: vec!["tool"],
+                ..TestPackage::depend("dep")
+            },
+            TestPackage::new("app"),
+            TestPackage::new("tool"),
+        ]);
+        let pkgs: Vec<_> = ["app", "dep", "lib"]
+            .map(|name| db.pkg(name).unwrap())
+            .into();
+        let lookup = TestLookup::default();
+        let context = RenderContext::new(&lookup, &pkgs, false);
+        let format = CompiledFormat::compile("%n").unwrap();
+        let fanout = |top| render_max_fanout(&db, &pkgs, top, &format, &context);
+        assert_eq!(fanout(2), ["3 lib", "1 dep"]);
+        assert_eq!(fanout(5), ["3 lib", "1 dep", "0 app"]);
+    }
+
+    // gone is not installed and left out, app requires lib twice over and is one node
+    #[test]
+    fn adjacency_json_lists_walked_edges_only() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["dep", "app"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage {
+                required_by: vec!["app", "gone"],
+                ..TestPackage::depend("dep")
+            },
+            TestPackage::new("app"),
+        ]);
+        let lib = db.pkg("lib").unwrap();
+        assert_eq!(
+            render_adjacency_json(&db, &[lib], &TRANSITIVE),
+            r#"{"lib":["dep","app"],"dep":["app"],"app":[]}"#
+        );
+    }
+
+    #[test]
+    fn mermaid_numbers_nodes_and_marks_explicit_ones() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["dep", "app"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage {
+                required_by: vec!["app"],
+                ..TestPackage::depend("dep")
+            },
+            TestPackage::new("app"),
+        ]);
+        let lib = db.pkg("lib").unwrap();
+        assert_eq!(
+            render_mermaid(&db, &[lib], &TRANSITIVE),
+            [
+                "graph TD",
+                "    n0[\"lib\"]",
+                "    n1[\"dep\"]",
+                "    n2[\"app\"]",
+                "    n0 --> n1",
+                "    n0 --> n2",
+                "    n1 --> n2",
+                "    classDef explicit font-weight:bold,stroke-width:3px",
+                "    class n2 explicit",
+            ]
+        );
+    }
+
+    // app reaches lib both through tool>=2 and through dep -> tool, the shorter way is kept
+    #[test]
+    fn paths_are_the_shortest_chain_of_dependencies() {
+        let db = TestDb(vec![
+            TestPackage {
+                depends: vec!["dep", "tool>=2"],
+                ..TestPackage::new("app")
+            },
+            TestPackage {
+                depends: vec!["tool"],
+                ..TestPackage::depend("dep")
+            },
+            TestPackage {
+                depends: vec!["lib"],
+                ..TestPackage::depend("tool")
+            },
+            TestPackage::depend("lib"),
+            TestPackage::new("other"),
+        ]);
+        let app = db.pkg("app").unwrap();
+        let path = |target| find_path(&db, app, target, &TRANSITIVE).map(|path| names(&path));
+        assert_eq!(path("lib").unwrap(), ["app", "tool", "lib"]);
+        assert_eq!(path("app").unwrap(), ["app"]);
+        assert_eq!(path("other"), None);
+
+        let walk = WalkOptions {
+            max_depth: Some(1),
+            ..TRANSITIVE
+        };
+        assert!(find_path(&db, app, "lib", &walk).is_none());
+    }
+
+    #[test]
+    fn sections_put_each_query_above_its_dependents() {
+        let reqby = || vec!["vim".to_string(), "gvim".to_string()];
+        assert_eq!(
+            required_by_entries(Some("gpm".to_string()), reqby(), false),
+            ["gpm", "  vim", "  gvim"]
+        );
+        assert_eq!(required_by_entries(None, reqby(), false), ["vim gvim"]);
+        assert_eq!(
+            required_by_entries(Some("ncurses".to_string()), Vec::new(), false),
+            ["ncurses"]
+        );
+        assert!(required_by_entries(None, Vec::new(), false).is_empty());
+    }
+
+    // two queries with the same dependents, which share a line per query unless null terminated
+    #[test]
+    fn null_terminated_dependents_are_separate_entries() {
+        let reqby = || vec!["vim".to_string(), "gvim".to_string()];
+        let entries: Vec<_> = [reqby(), reqby()]
+            .into_iter()
+            .flat_map(|reqby| required_by_entries(None, reqby, true))
+            .collect();
+        assert_eq!(entries, ["vim", "gvim", "vim", "gvim"]);
+        let entries: Vec<_> = [reqby(), reqby()]
+            .into_iter()
+            .flat_map(|reqby| required_by_entries(None, reqby, false))
+            .collect();
+        assert_eq!(entries, ["vim gvim", "vim gvim"]);
+    }
+
+    #[test]
+    fn packages_classify_by_their_direct_dependents() {
+        let required_by = |required_by| TestPackage {
+            required_by,
+            ..TestPackage::depend("lib")
+        };
+        assert_eq!(classify_package(&required_by(vec![]), 2), "leaf");
+        assert_eq!(classify_package(&required_by(vec!["a", "b"]), 2), "branch");
+        assert_eq!(
+            classify_package(&required_by(vec!["a", "b", "c"]), 2),
+            "hub"
+        );
+    }
+
+    #[test]
+    fn mismarked_packages_get_the_other_reason_suggested() {
+        let required = TestPackage {
+            required_by: vec!["vim", "gvim"],
+            ..TestPackage::new("gpm")
+        };
+        assert_eq!(
+            suggest_reason(&required).unwrap(),
+            "gpm: Explicit -> Depend, required by vim, gvim"
+        );
+        assert_eq!(
+            suggest_reason(&TestPackage::depend("leftover")).unwrap(),
+            "leftover: Depend -> Explicit, nothing requires it"
+        );
+        assert!(suggest_reason(&TestPackage::new("vim")).is_none());
+    }
+
+    // a package matched by two queries, the first match stays in place
+    #[test]
+    fn packages_matched_twice_are_listed_once() {
+        let (vim, gvim, gpm) = (
+            TestPackage::new("vim"),
+            TestPackage::new("gvim"),
+            TestPackage::depend("gpm"),
+        );
+        let mut pkgs = vec![&vim, &gpm, &gvim, &vim, &gpm];
+        dedup_by_name(&mut pkgs);
+        assert_eq!(names(&pkgs), ["vim", "gpm", "gvim"]);
+    }
+
+    #[test]
+    fn dependents_are_kept_when_in_the_wanted_repo() {
+        let in_repo = |wanted, repo| {
+            let asked = Cell::new(false);
+            let kept = in_wanted_repo(wanted, || {
+                asked.set(true);
+                repo
+            });
+            (kept, asked.get())
+        };
+        assert_eq!(in_repo(Some("extra"), "extra"), (true, true));
+        assert_eq!(in_repo(Some("extra"), "core"), (false, true));
+        assert_eq!(in_repo(Some("extra"), FOREIGN_REPO), (false, true));
+        assert_eq!(in_repo(None, "core"), (true, false));
+    }
+
+    // groups come in the order they are first seen, a package in two is listed under both
+    #[test]
+    fn group_members_are_listed_below_each_group() {
+        let grouped = |name, groups| TestPackage {
+            groups,
+            ..TestPackage::new(name)
+        };
+        let pkgs = [
+            grouped("xorg-server", vec!["xorg"]),
+            grouped("vim", vec![]),
+            grouped("xorg-xinit", vec!["xorg", "xorg-apps"]),
+        ];
+        let pkgs: Vec<_> = pkgs.iter().collect();
+        let lookup = TestLookup::default();
+        let context = RenderContext::new(&lookup, &pkgs, false);
+        let format = CompiledFormat::compile("%n").unwrap();
+        assert_eq!(
+            render_group_members(&pkgs, &format, &context),
+            [
+                "xorg",
+                "  xorg-server",
+                "  xorg-xinit",
+                "xorg-apps",
+                "  xorg-xinit"
+            ]
+        );
+    }
+
+    #[test]
+    fn diffs_remove_then_add_by_name() {
+        let (vim, gvim, nano, emacs) = (
+            TestPackage::new("vim"),
+            TestPackage::new("gvim"),
+            TestPackage::new("nano"),
+            TestPackage::new("emacs"),
+        );
+        let before = [&vim, &gvim, &nano];
+        let after = [&emacs, &vim];
+        let all = [&vim, &gvim, &nano, &emacs];
+        let lookup = TestLookup::default();
+        let context = RenderContext::new(&lookup, &all, false);
+        let format = CompiledFormat::compile("%n").unwrap();
+        assert_eq!(
+            render_diff(&before, &after, &format, &context),
+            ["- gvim", "- nano", "+ emacs"]
+        );
+        assert!(render_diff(&before, &before, &format, &context).is_empty());
+    }
+
+    // lib is both queried and in the footprint of app, and lib leads back to app
+    #[test]
+    fn footprints_add_every_dependency_once() {
+        let db = TestDb(vec![
+            TestPackage::new("vim"),
+            TestPackage {
+                depends: vec!["lib", "tool"],
+                ..TestPackage::new("app")
+            },
+            TestPackage {
+                depends: vec!["lib>=2"],
+                ..TestPackage::depend("tool")
+            },
+            TestPackage {
+                depends: vec!["app"],
+                ..TestPackage::depend("lib")
+            },
+        ]);
+        let mut pkgs = vec![db.pkg("vim").unwrap(), db.pkg("lib").unwrap()];
+        add_footprint(&db, &mut pkgs, db.pkg("app").unwrap());
+        assert_eq!(names(&pkgs), ["vim", "lib", "app", "tool"]);
+    }
+
+    #[test]
+    fn explicit_leaves_are_explicit_packages_nothing_requires() {
+        let required = |pkg| TestPackage {
+            required_by: vec!["app"],
+            ..pkg
+        };
+        let pkgs = [
+            TestPackage::new("vim"),
+            required(TestPackage::new("gpm")),
+            TestPackage::depend("leftover"),
+            required(TestPackage::depend("lib")),
+        ];
+        let leaves: Vec<_> = pkgs
+            .iter()
+            .filter(|pkg| is_listed(*pkg, ReasonSelector::Explicit, true, false))
+            .collect();
+        assert_eq!(names(&leaves), ["vim"]);
+    }
+
+    #[test]
+    fn orphans_are_dependencies_nothing_requires() {
+        let required = |pkg| TestPackage {
+            required_by: vec!["app"],
+            ..pkg
+        };
+        let pkgs = [
+            TestPackage::new("vim"),
+            TestPackage::depend("leftover"),
+            required(TestPackage::depend("lib")),
+        ];
+        assert!(pkgs[1].is_orphan());
+        assert!(!pkgs[0].is_orphan() && !pkgs[2].is_orphan());
+        let orphans: Vec<_> = pkgs
+            .iter()
+            .filter(|pkg| is_listed(*pkg, ReasonSelector::Depend, false, true))
+            .collect();
+        assert_eq!(names(&orphans), ["leftover"]);
+    }
+
+    #[test]
+    fn reason_summaries_tally_both_reasons() {
+        let pkgs = [
+            TestPackage::new("vim"),
+            TestPackage::depend("gpm"),
+            TestPackage::new("nano"),
+            TestPackage::depend("ncurses"),
+            TestPackage::depend("glibc"),
+        ];
+        let pkgs: Vec<_> = pkgs.iter().collect();
+        assert_eq!(summarize_reasons(&pkgs), "explicit: 2, depend: 3");
+        assert_eq!(
+            summarize_reasons::<TestPackage>(&[]),
+            "explicit: 0, depend: 0"
+        );
+    }
+
+    // dep is required by two packages in turn, app and tool by none
+    #[test]
+    fn dependents_are_annotated_with_their_own_dependents() {
+        let db = TestDb(vec![
+            TestPackage {
+                required_by: vec!["dep", "app"],
+                ..TestPackage::depend("lib")
+            },
+            TestPackage {
+                required_by: vec!["app", "tool"],
+                ..TestPackage::depend("dep")
+            },
+            TestPackage::new("app"),
+            TestPackage::new("tool"),
+        ]);
+        let reqby = find_required_by(
+            &db,
+            db.pkg("lib").unwrap(),
+            ReasonSelector::Both,
+            &TRANSITIVE,
+        );
+        let dependents: Vec<_> = reqby.iter().map(|r| r.pkg).collect();
+        let lookup = TestLookup::default();
+        let context = RenderContext::new(&lookup, &dependents, false);
+        let format = CompiledFormat::compile("%n").unwrap();
+        let drawn: Vec<_> = reqby
+            .into_iter()
+            .map(|r| r.draw(&format, &context, false, true, false))
+            .collect();
+        assert_eq!(drawn, ["dep(2)", "app(0)", "tool(0)"]);
+    }
+
+    #[test]
+    fn drawn_dependents_are_colored_by_reason() {
+        let (vim, gpm) = (TestPackage::new("vim"), TestPackage::depend("gpm"));
+        let pkgs = [&vim, &gpm];
+        let lookup = TestLookup::default();
+        let context = RenderContext::new(&lookup, &pkgs, true);
+        let draw = |text, pkg, optional| {
+            let format = CompiledFormat::compile(text).unwrap();
+            let item = ReqByItem {
+                optional,
+                ..item(pkg, 1)
+            };
+            item.draw(&format, &context, true, false, false)
+        };
+        assert_eq!(draw("%n", &vim, false), "\x1b[33mvim\x1b[m");
+        assert_eq!(draw("%n", &gpm, false), "gpm");
+        assert_eq!(draw("%n", &gpm, true), "\x1b[36mgpm\x1b[m");
+        // the format colors the reason itself
+        assert_eq!(
+            draw("%n %{reason:color}", &vim, false),
+            "vim \x1b[32mExplicit\x1b[m"
+        );
+    }
+}
@@ -2,19 +2,24 @@ use alpm::Alpm;
 use std::env;
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::process::ExitCode;
 
 mod argparse;
 mod listing;
 mod output;
 mod query;
+mod timing;
 
 #[derive(Debug)]
 enum ProgramError {
     NoPackagesFound,
-    InvalidFormat(String),
+    InvalidFormat(String, output::FormatError),
     InvalidRequest(String),
     InvalidQuery(query::ParseError),
+    UnsafeForXargs(String),
+    Input(io::Error),
+    Output(io::Error),
 }
 
 impl Error for ProgramError {}
@@ -24,9 +29,12 @@ impl fmt::Display for ProgramError {
         use ProgramError::*;
         match self {
             NoPackagesFound => write!(f, "no matching packages found"),
-            InvalidFormat(format) => write!(f, "invalid format '{format}'"),
+            InvalidFormat(format, err) => write!(f, "invalid format '{format}': {err}"),
             InvalidRequest(explination) => write!(f, "{}", explination),
             InvalidQuery(parse_error) => parse_error.fmt(f),
+            UnsafeForXargs(line) => write!(f, "'{line}' contains whitespace, xargs would split it"),
+            Input(err) => write!(f, "failed to read input: {}", err),
+            Output(err) => write!(f, "failed to write output: {}", err),
         }
     }
 }
@@ -37,6 +45,21 @@ impl From<query::ParseError> for ProgramError {
     }
 }
 
+impl From<io::Error> for ProgramError {
+    fn from(err: io::Error) -> ProgramError {
+        ProgramError::Output(err)
+    }
+}
+
+impl From<output::PrintError> for ProgramError {
+    fn from(err: output::PrintError) -> ProgramError {
+        match err {
+            output::PrintError::Io(err) => ProgramError::Output(err),
+            output::PrintError::UnsafeForXargs(line) => ProgramError::UnsafeForXargs(line),
+        }
+    }
+}
+
 fn print_helptext(option_text: String) {
     let lines = [
         "usage: pacman-blame [options] QUERY...".to_string(),
@@ -45,13 +68,33 @@ fn print_helptext(option_text: String) {
         "".to_string(),
         "QUERY:".to_string(),
         "[package:]<package-name>  search the database for the exact name".to_string(),
+        "[package:]<pattern>       names matching a pattern with * and ?, \\* is a literal *"
+            .to_string(),
+        "desc:<text>               packages whose description contains the text or pattern"
+            .to_string(),
+        "provides-count:[<|>]<n>   packages providing less, more or exactly n packages".to_string(),
+        "reason:<reason>           packages installed with reason explicit or dependency"
+            .to_string(),
+        "group:<group>             installed members of the group".to_string(),
+        "-                         read queries from stdin, one per line".to_string(),
+        "--                        the rest are queries, even if they start with -".to_string(),
         "".to_string(),
         "Use -h|--help after an option for more details".to_string(),
+        format!(
+            "Set {}=list to list packages when no command is given",
+            argparse::ENV_DEFAULT_API
+        ),
     ];
 
     println!("{}", lines.join("\n"));
 }
 
+fn check_format(format: &str) -> Result<(), ProgramError> {
+    output::CompiledFormat::compile(format)
+        .map(|_| ())
+        .map_err(|err| ProgramError::InvalidFormat(format.to_string(), err))
+}
+
 fn main() -> ExitCode {
     let (api, common) = match argparse::parse_args(env::args().skip(1)) {
         Ok(args) => args,
@@ -70,20 +113,46 @@ fn main() -> ExitCode {
             argparse::print_argument_group(Some(opt.as_str()))
                 .expect("this should be supplied with a valid option"),
         ),
+        argparse::Api::Version => println!("pacman-blame {}", env!("CARGO_PKG_VERSION")),
+        argparse::Api::ListFormats => println!("{}", output::print_format_tokens()),
+        // no database is opened, so this is cheap enough to run on every keystroke
+        argparse::Api::CheckFormat => {
+            let format = common.format.expect("--check-format needs a --format");
+            if let Err(err) = check_format(&format) {
+                eprintln!("{}", err);
+                return ExitCode::from(3);
+            }
+            println!("ok");
+        }
         argparse::Api::List(list) => {
+            let mut timings = timing::Timings::new(common.benchmark);
             let Ok(handle) = Alpm::new("/", "/var/lib/pacman") else {
                 eprintln!("could not connect to package database");
                 return ExitCode::from(2);
             };
-            match listing::list_packages(handle, list, common) {
-                Ok(_) => (),
-                Err(err) => {
-                    eprintln!("{}", err);
-                    return ExitCode::from(3);
-                }
+            timings.lap("db connection");
+            let result = listing::list_packages(handle, *list, common, &mut timings);
+            timings.report();
+            if let Err(err) = result {
+                eprintln!("{}", err);
+                return ExitCode::from(3);
             }
         }
     }
 
     ExitCode::from(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_are_checked_without_a_db() {
+        assert!(check_format("%n %{v:cmp=1.0}").is_ok());
+        assert!(matches!(
+            check_format("%n %q"),
+            Err(ProgramError::InvalidFormat(format, _)) if format == "%n %q"
+        ));
+    }
+}
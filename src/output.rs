@@ -1,12 +1,728 @@
-use alpm::{PackageReason, Pkg};
+use super::listing::{all_depends, count_required_by};
+use alpm::{Alpm, Package, PackageReason, Pkg};
+use std::cell::{OnceCell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::default::Default;
+use std::env;
+use std::error;
+use std::ffi::CString;
+use std::fmt;
+use std::io::{self, BufWriter, IsTerminal, StdoutLock, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 enum Format<'a> {
     Text(&'a str),
     Name,
     Summary,
+    LocalizedSummary(&'a str),
     Reason,
+    ReasonCount,
+    ReasonColor,
+    InstalledReason,
     Version,
+    Date {
+        field: DateField,
+        pattern: &'a str,
+    },
+    Age,
+    Url,
+    UrlHost,
+    ShortHash,
+    Arch,
+    Packager,
+    Size,
+    SizeBytes,
+    SizePercent,
+    SizeBar,
+    Depends,
+    DepsTree,
+    Compression,
+    RequiredBy,
+    Multilib,
+    NameLink,
+    VersionCmp(&'a str),
+    Depth,
+    License,
+    LicenseSpdx,
+    RequiredByCount,
+    RequiredByTransitive,
+    Groups,
+    GroupsCount,
+    GroupsFirst,
+    Backup,
+    OptionalReasons,
+    ProvidesCount,
+    Conditional {
+        condition: Condition,
+        then: &'a str,
+        otherwise: &'a str,
+    },
+    Modified(Box<Format<'a>>, Modifier),
+}
+
+#[derive(Clone, Copy)]
+enum DateField {
+    Build,
+    Install,
+}
+
+impl DateField {
+    fn timestamp(self, pkg: &impl PackageFields) -> Option<i64> {
+        match self {
+            DateField::Build => pkg.build_date(),
+            DateField::Install => pkg.install_date(),
+        }
+    }
+}
+
+fn wrap(text: &str, width: usize) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines.join("\n")
+}
+
+// applied to the rendered value of a token, as in %{n:upper}
+enum Modifier {
+    Lower,
+    Upper,
+    // padded to at least this many characters, longer values are kept whole
+    AlignLeft(usize),
+    AlignRight(usize),
+    // lines of at most this many characters, a word that is longer gets a line of its own
+    Wrap(usize),
+    Truncate(usize),
+    // cut off like Truncate, the text marking the cut counts towards the width
+    Ellipsis(usize, String),
+    // exactly this many characters, the terminal width less the literal text of the format
+    Fit(usize),
+    // aligned to the widest value of the token among the listed packages
+    LeftJustify,
+    RightJustify,
+}
+
+impl Modifier {
+    fn apply(&self, value: String) -> String {
+        match self {
+            Modifier::Lower => value.to_lowercase(),
+            Modifier::Upper => value.to_uppercase(),
+            Modifier::AlignLeft(width) => format!("{:<width$}", value),
+            Modifier::AlignRight(width) => format!("{:>width$}", value),
+            Modifier::Wrap(width) => wrap(&value, *width),
+            Modifier::Truncate(width) => value.chars().take(*width).collect(),
+            Modifier::Ellipsis(width, _) if value.chars().count() <= *width => value,
+            Modifier::Ellipsis(width, ellipsis) => {
+                let kept = width.saturating_sub(ellipsis.chars().count());
+                value.chars().take(kept).chain(ellipsis.chars()).collect()
+            }
+            Modifier::Fit(width) => {
+                let value: String = value.chars().take(*width).collect();
+                format!("{:<width$}", value)
+            }
+            // the width depends on the other packages, Format::render measures it
+            Modifier::LeftJustify | Modifier::RightJustify => value,
+        }
+    }
+}
+
+// the index after i that doesn't split a character, one past the end if there is none
+fn next_boundary(text: &str, i: usize) -> usize {
+    (i + 1..=text.len())
+        .find(|j| text.is_char_boundary(*j))
+        .unwrap_or(text.len() + 1)
+}
+
+const DEFAULT_ELLIPSIS: &str = "...";
+
+const DEFAULT_COLUMNS: usize = 80;
+
+fn terminal_width() -> usize {
+    width_from(
+        env::var("COLUMNS").ok().as_deref(),
+        io::stdout().is_terminal(),
+    )
+}
+
+// $COLUMNS is only trusted when printing to a terminal, pipes get the default
+fn width_from(columns: Option<&str>, is_tty: bool) -> usize {
+    columns
+        .filter(|_| is_tty)
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(DEFAULT_COLUMNS)
+}
+
+enum Condition {
+    Reason(PackageReason),
+}
+
+impl Condition {
+    fn test(&self, pkg: &impl PackageFields) -> bool {
+        match self {
+            Condition::Reason(reason) => pkg.reason() == *reason,
+        }
+    }
+}
+
+// a single character key is spelled both %k and %{k}, longer keys only %{key}
+struct Token {
+    key: &'static str,
+    comment: &'static str,
+    format: fn() -> Format<'static>,
+}
+
+impl Token {
+    fn matches(&self, tokens: &str) -> bool {
+        let braced = tokens.strip_prefix("%{").and_then(|t| t.strip_suffix("}"));
+        let short = tokens.strip_prefix("%").filter(|_| self.key.len() == 1);
+        braced == Some(self.key) || short == Some(self.key)
+    }
+
+    fn spelling(&self) -> String {
+        if self.key.len() == 1 {
+            format!("%{}|%{{{}}}", self.key, self.key)
+        } else {
+            format!("%{{{}}}", self.key)
+        }
+    }
+}
+
+const TOKENS: [Token; 38] = [
+    Token {
+        key: "n",
+        comment: "package name",
+        format: || Format::Name,
+    },
+    Token {
+        key: "s",
+        comment: "package summary",
+        format: || Format::Summary,
+    },
+    Token {
+        key: "r",
+        comment: "install reason (Explicit or Depend)",
+        format: || Format::Reason,
+    },
+    Token {
+        key: "reason:count",
+        comment: "number of installed packages with the same install reason",
+        format: || Format::ReasonCount,
+    },
+    Token {
+        key: "reason:color",
+        comment: "install reason, green if explicit and gray if not with --color",
+        format: || Format::ReasonColor,
+    },
+    Token {
+        key: "installed-explicitly-elsewhere",
+        comment: "explicit, dependency or not-installed according to the local db",
+        format: || Format::InstalledReason,
+    },
+    Token {
+        key: "v",
+        comment: "package version",
+        format: || Format::Version,
+    },
+    Token {
+        key: "b",
+        comment: "build date",
+        format: || Format::Date {
+            field: DateField::Build,
+            pattern: ISO_8601,
+        },
+    },
+    Token {
+        key: "i",
+        comment: "install date",
+        format: || Format::Date {
+            field: DateField::Install,
+            pattern: ISO_8601,
+        },
+    },
+    Token {
+        key: "age",
+        comment: "time since the install, e.g. 3 weeks",
+        format: || Format::Age,
+    },
+    Token {
+        key: "u",
+        comment: "upstream url",
+        format: || Format::Url,
+    },
+    Token {
+        key: "url:host",
+        comment: "host name of the upstream url",
+        format: || Format::UrlHost,
+    },
+    Token {
+        key: "hash:short",
+        comment: "8 hex digits identifying the name and version",
+        format: || Format::ShortHash,
+    },
+    Token {
+        key: "a",
+        comment: "architecture, any if the package has none",
+        format: || Format::Arch,
+    },
+    Token {
+        key: "p",
+        comment: "packager",
+        format: || Format::Packager,
+    },
+    Token {
+        key: "z",
+        comment: "installed size, e.g. 1.2 MiB",
+        format: || Format::Size,
+    },
+    Token {
+        key: "z:bytes",
+        comment: "installed size in bytes",
+        format: || Format::SizeBytes,
+    },
+    Token {
+        key: "size:pct",
+        comment: "installed size as a share of all listed packages",
+        format: || Format::SizePercent,
+    },
+    Token {
+        key: "size:bar",
+        comment: "installed size as a bar, the largest listed package fills it",
+        format: || Format::SizeBar,
+    },
+    Token {
+        key: "D",
+        comment: "direct dependencies with their version constraints",
+        format: || Format::Depends,
+    },
+    Token {
+        key: "deps:tree",
+        comment: "all transitive dependencies as an inline list",
+        format: || Format::DepsTree,
+    },
+    Token {
+        key: "compression",
+        comment: "download size as a share of the installed size",
+        format: || Format::Compression,
+    },
+    Token {
+        key: "reqby",
+        comment: "packages that directly require the package",
+        format: || Format::RequiredBy,
+    },
+    Token {
+        key: "arch:multilib",
+        comment: "lib32 for 32-bit multilib packages, otherwise empty",
+        format: || Format::Multilib,
+    },
+    Token {
+        key: "n:pad-to-max",
+        comment: "same as %{n:ljust}",
+        format: || Format::Modified(Box::new(Format::Name), Modifier::LeftJustify),
+    },
+    Token {
+        key: "n:link",
+        comment: "package name linking to its url with --color",
+        format: || Format::NameLink,
+    },
+    Token {
+        key: "depth",
+        comment: "distance to the queried package with --required-by, otherwise 0",
+        format: || Format::Depth,
+    },
+    Token {
+        key: "l",
+        comment: "package licenses",
+        format: || Format::License,
+    },
+    Token {
+        key: "license:spdx",
+        comment: "licenses translated to SPDX identifiers where known",
+        format: || Format::LicenseSpdx,
+    },
+    Token {
+        key: "R",
+        comment: "number of packages that directly require the package",
+        format: || Format::RequiredByCount,
+    },
+    Token {
+        key: "reqby:transitive",
+        comment: "number of packages that (transitively) require the package",
+        format: || Format::RequiredByTransitive,
+    },
+    Token {
+        key: "g",
+        comment: "groups the package is part of",
+        format: || Format::Groups,
+    },
+    Token {
+        key: "groups",
+        comment: "same as %g",
+        format: || Format::Groups,
+    },
+    Token {
+        key: "groups:count",
+        comment: "number of groups the package is part of",
+        format: || Format::GroupsCount,
+    },
+    Token {
+        key: "groups:first",
+        comment: "first group the package is part of, otherwise empty",
+        format: || Format::GroupsFirst,
+    },
+    Token {
+        key: "backup",
+        comment: "config files pacman keeps on upgrade and removal",
+        format: || Format::Backup,
+    },
+    Token {
+        key: "o:reasons",
+        comment: "why the optional dependencies are suggested, those without a reason are left out",
+        format: || Format::OptionalReasons,
+    },
+    Token {
+        key: "provides:count",
+        comment: "number of virtual packages the package provides",
+        format: || Format::ProvidesCount,
+    },
+];
+
+// (spelling, replacement, comment)
+const ESCAPES: [(&str, &str, &str); 6] = [
+    ("%%", "%", "a literal '%'"),
+    ("\\t", "\t", "tab"),
+    ("\\n", "\n", "newline"),
+    ("\\\\", "\\", "a literal '\\'"),
+    ("\\r", "\r", "carriage return"),
+    ("\\0", "\0", "null byte"),
+];
+
+// a token taking an argument, spelled PREFIX ARGUMENT }, parse gets what stands for ARGUMENT
+struct ArgumentToken {
+    prefix: &'static str,
+    argument: &'static str,
+    comment: &'static str,
+    parse: for<'a> fn(&'a str) -> ParseStatus<'a>,
+}
+
+impl ArgumentToken {
+    fn spelling(&self) -> String {
+        format!("{}{}}}", self.prefix, self.argument)
+    }
+}
+
+const ARGUMENT_TOKENS: [ArgumentToken; 6] = [
+    ArgumentToken {
+        prefix: "%{v:cmp=",
+        argument: "VERSION",
+        comment: "older, equal or newer when comparing the version to VERSION",
+        parse: |version| ParseStatus::Correct(Format::VersionCmp(version)),
+    },
+    ArgumentToken {
+        prefix: "%{?",
+        argument: "r=VALUE:THEN|ELSE",
+        comment: "THEN if the reason is VALUE, otherwise ELSE",
+        parse: |body| Format::parse_conditional(body),
+    },
+    ArgumentToken {
+        prefix: "%{date:",
+        argument: "FORMAT",
+        comment: "install date formatted by strftime FORMAT",
+        parse: |pattern| Format::parse_date(DateField::Install, pattern),
+    },
+    ArgumentToken {
+        prefix: "%{i:date=",
+        argument: "FORMAT",
+        comment: "same as %{date:FORMAT}",
+        parse: |pattern| Format::parse_date(DateField::Install, pattern),
+    },
+    ArgumentToken {
+        prefix: "%{s:lang=",
+        argument: "LANG",
+        comment: "summary in LANG where available, otherwise the default",
+        parse: |lang| Format::parse_localized_summary(lang),
+    },
+    ArgumentToken {
+        prefix: "%{b:date=",
+        argument: "FORMAT",
+        comment: "build date formatted by strftime FORMAT",
+        parse: |pattern| Format::parse_date(DateField::Build, pattern),
+    },
+];
+
+// a modifier spelled %{KEY SUFFIX}, parse is given one colon separated segment of the suffix and
+// the modifier before it. one that replaces takes the place of that modifier, as wrap and trunc
+// change what the width right before them does
+struct ModifierRule {
+    suffix: &'static str,
+    comment: &'static str,
+    parse: fn(&str, Option<&Modifier>) -> Option<Modifier>,
+    replaces: bool,
+}
+
+const MODIFIERS: [ModifierRule; 10] = [
+    ModifierRule {
+        suffix: ":lower",
+        comment: "any token in lowercase",
+        parse: |segment, _| (segment == "lower").then_some(Modifier::Lower),
+        replaces: false,
+    },
+    ModifierRule {
+        suffix: ":upper",
+        comment: "any token in uppercase",
+        parse: |segment, _| (segment == "upper").then_some(Modifier::Upper),
+        replaces: false,
+    },
+    ModifierRule {
+        suffix: ":WIDTH",
+        comment: "any token padded to WIDTH characters",
+        parse: |segment, _| segment.parse().ok().map(Modifier::AlignLeft),
+        replaces: false,
+    },
+    ModifierRule {
+        suffix: ":>WIDTH",
+        comment: "any token right aligned to WIDTH characters",
+        parse: |segment, _| {
+            let width = segment.strip_prefix('>')?;
+            width.parse().ok().map(Modifier::AlignRight)
+        },
+        replaces: false,
+    },
+    ModifierRule {
+        suffix: ":WIDTH:wrap",
+        comment: "any token wrapped into lines of WIDTH characters",
+        parse: |segment, previous| match (segment, previous) {
+            ("wrap", Some(Modifier::AlignLeft(width))) => Some(Modifier::Wrap(*width)),
+            _ => None,
+        },
+        replaces: true,
+    },
+    ModifierRule {
+        suffix: ":WIDTH:trunc",
+        comment: "any token cut off after WIDTH characters",
+        parse: |segment, previous| match (segment, previous) {
+            ("trunc", Some(Modifier::AlignLeft(width))) => Some(Modifier::Truncate(*width)),
+            _ => None,
+        },
+        replaces: true,
+    },
+    ModifierRule {
+        suffix: ":WIDTH:ellipsis=STR",
+        comment: "any token cut off to WIDTH characters ending in STR, ... if left out",
+        parse: |segment, previous| {
+            let ellipsis = match segment.strip_prefix("ellipsis") {
+                Some("") => DEFAULT_ELLIPSIS,
+                Some(rest) => rest.strip_prefix('=')?,
+                None => return None,
+            };
+            match previous {
+                Some(Modifier::AlignLeft(width) | Modifier::Truncate(width)) => {
+                    Some(Modifier::Ellipsis(*width, ellipsis.to_string()))
+                }
+                _ => None,
+            }
+        },
+        replaces: true,
+    },
+    ModifierRule {
+        suffix: ":ljust",
+        comment: "any token padded to its widest value among the listed packages",
+        parse: |segment, _| (segment == "ljust").then_some(Modifier::LeftJustify),
+        replaces: false,
+    },
+    ModifierRule {
+        suffix: ":rjust",
+        comment: "any token right aligned to its widest value among the listed packages",
+        parse: |segment, _| (segment == "rjust").then_some(Modifier::RightJustify),
+        replaces: false,
+    },
+    ModifierRule {
+        suffix: ":width-env",
+        comment: "any token fit to $COLUMNS, less the rest of the format",
+        parse: |segment, _| (segment == "width-env").then(|| Modifier::Fit(terminal_width())),
+        replaces: false,
+    },
+];
+
+// pacman license names and their closest SPDX identifier
+const SPDX_LICENSES: [(&str, &str); 16] = [
+    ("GPL", "GPL-2.0-or-later"),
+    ("GPL2", "GPL-2.0-only"),
+    ("GPL3", "GPL-3.0-only"),
+    ("LGPL", "LGPL-2.1-or-later"),
+    ("LGPL2.1", "LGPL-2.1-only"),
+    ("LGPL3", "LGPL-3.0-only"),
+    ("AGPL3", "AGPL-3.0-only"),
+    ("BSD", "BSD-3-Clause"),
+    ("APACHE", "Apache-2.0"),
+    ("Apache", "Apache-2.0"),
+    ("MPL", "MPL-1.1"),
+    ("MPL2", "MPL-2.0"),
+    ("PerlArtistic", "Artistic-1.0-Perl"),
+    ("PSF", "Python-2.0"),
+    ("ZLIB", "Zlib"),
+    ("FDL1.3", "GFDL-1.3-only"),
+];
+
+fn human_size(bytes: i64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // compare what gets printed, so 1048575 bytes shows as 1.0 MiB rather than 1024.0 KiB
+    while (size * 10.0).round() >= 10240.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// how a package is installed, if it is installed at all
+fn installed_reason(reason: Option<PackageReason>) -> &'static str {
+    match reason {
+        Some(PackageReason::Explicit) => "explicit",
+        Some(PackageReason::Depend) => "dependency",
+        None => "not-installed",
+    }
+}
+
+const ISO_8601: &str = "%Y-%m-%dT%H:%M:%S%z";
+
+// the strftime conversions that can follow a '%' in %{date:FORMAT}
+const STRFTIME_CONVERSIONS: &str = "aAbBcCdDeFgGhHIjmMnprRsStTuUVwWxXyYzZ%";
+
+// a unix timestamp in local time, formatted by strftime
+fn format_date(timestamp: i64, pattern: &str) -> String {
+    let Ok(pattern) = CString::new(pattern) else {
+        return String::new();
+    };
+    let time = timestamp as libc::time_t;
+    let mut buf = [0u8; 256];
+    let len = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return String::new();
+        }
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            pattern.as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// rounded down to the largest unit that fits, months and years are 30 and 365 days
+fn human_age(seconds: i64) -> String {
+    const UNITS: [(&str, i64); 6] = [
+        ("year", 365 * 86400),
+        ("month", 30 * 86400),
+        ("week", 7 * 86400),
+        ("day", 86400),
+        ("hour", 3600),
+        ("minute", 60),
+    ];
+    let (unit, count) = UNITS
+        .iter()
+        .find(|(_, length)| seconds >= *length)
+        .map(|(unit, length)| (*unit, seconds / length))
+        .unwrap_or(("second", seconds.max(0)));
+    if count == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", count, unit)
+    }
+}
+
+// share of the total size of the listed packages
+fn size_percent(size: i64, total_size: i64) -> Option<f64> {
+    (total_size > 0).then(|| 100.0 * size as f64 / total_size as f64)
+}
+
+// download size as a share of the installed size, unknown if either size is
+fn compression_ratio(download_size: i64, installed_size: i64) -> Option<f64> {
+    (download_size > 0 && installed_size > 0)
+        .then(|| 100.0 * download_size as f64 / installed_size as f64)
+}
+
+const SIZE_BAR_WIDTH: usize = 20;
+
+// the partially filled cell at the end of a bar, in eighths
+const PARTIAL_BLOCKS: [char; 7] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+fn size_bar(size: i64, max_size: i64) -> String {
+    if max_size <= 0 || size <= 0 {
+        return String::new();
+    }
+    let eighths = (size as f64 / max_size as f64 * (SIZE_BAR_WIDTH * 8) as f64).round() as usize;
+    let mut bar = "█".repeat(eighths / 8);
+    if !eighths.is_multiple_of(8) {
+        bar.push(PARTIAL_BLOCKS[eighths % 8 - 1]);
+    }
+    bar
+}
+
+const FNV_OFFSET_BASIS: u32 = 0x811c9dc5;
+const FNV_PRIME: u32 = 0x01000193;
+
+// 32 bit FNV-1a, unlike the hashers of std it stays the same between rust releases. the null
+// byte keeps foo-1 + 2 apart from foo + 12
+fn short_hash(name: &str, version: &str) -> String {
+    let hash = name
+        .bytes()
+        .chain([0])
+        .chain(version.bytes())
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(FNV_PRIME)
+        });
+    format!("{:08x}", hash)
+}
+
+// scheme://user@host:port/path, anything without a scheme is returned as is
+fn url_host(url: &str) -> &str {
+    let Some((_, rest)) = url.split_once("://") else {
+        return url;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    host.split_once(':').map_or(host, |(host, _)| host)
+}
+
+// an OSC-8 hyperlink, which terminals without support print as the plain text
+fn hyperlink(url: &str, text: &str) -> String {
+    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+}
+
+// how a version relates to the one it is compared to
+fn version_relation(order: Ordering) -> &'static str {
+    match order {
+        Ordering::Less => "older",
+        Ordering::Equal => "equal",
+        Ordering::Greater => "newer",
+    }
+}
+
+fn spdx_license(license: &str) -> String {
+    if let Some((_, spdx)) = SPDX_LICENSES.iter().find(|(name, _)| *name == license) {
+        spdx.to_string()
+    } else if license == "custom" {
+        "LicenseRef-custom".to_string()
+    } else if let Some(name) = license.strip_prefix("custom:") {
+        format!("LicenseRef-{}", name)
+    } else {
+        license.to_string()
+    }
 }
 
 enum ParseStatus<'a> {
@@ -16,33 +732,611 @@ enum ParseStatus<'a> {
 }
 
 impl<'a> Format<'a> {
+    // the token below any modifiers, which nest once per modifier
+    fn base(&self) -> &Format<'a> {
+        match self {
+            Format::Modified(inner, _) => inner.base(),
+            format => format,
+        }
+    }
+
+    // width-env modifiers, at any depth, leave room for fixed characters
+    fn shrink_fit(&mut self, fixed: usize) {
+        if let Format::Modified(inner, modifier) = self {
+            if let Modifier::Fit(width) = modifier {
+                *width = width.saturating_sub(fixed);
+            }
+            inner.shrink_fit(fixed);
+        }
+    }
+
     fn parse_token_slice(tokens: &'a str) -> ParseStatus<'a> {
+        if let Some(token) = TOKENS.iter().find(|t| t.matches(tokens)) {
+            return ParseStatus::Correct((token.format)());
+        }
+        if let Some((_, text, _)) = ESCAPES.iter().find(|(e, _, _)| *e == tokens) {
+            return ParseStatus::Correct(Format::Text(text));
+        }
+        let argument = ARGUMENT_TOKENS.iter().find_map(|token| {
+            let argument = tokens.strip_prefix(token.prefix)?.strip_suffix("}")?;
+            Some((token, argument))
+        });
+        if let Some((token, argument)) = argument {
+            return (token.parse)(argument);
+        }
+
         match tokens {
             "" => ParseStatus::NeedMore,
             "%" => ParseStatus::NeedMore,
-            "%%" => ParseStatus::Correct(Format::Text(&tokens[0..1])),
-            "%n" | "%{n}" => ParseStatus::Correct(Format::Name),
-            "%s" | "%{s}" => ParseStatus::Correct(Format::Summary),
-            "%r" | "%{r}" => ParseStatus::Correct(Format::Reason),
-            "%v" | "%{v}" => ParseStatus::Correct(Format::Version),
             "%{" => ParseStatus::NeedMore,
             s if s.starts_with("%{") => {
                 if s.ends_with("}") {
-                    ParseStatus::Invalid
+                    Format::parse_modified(&s[2..s.len() - 1])
                 } else {
                     ParseStatus::NeedMore
                 }
             }
             s if s.starts_with("%") => ParseStatus::Invalid,
+            "\\" => ParseStatus::NeedMore,
             s => ParseStatus::Correct(Format::Text(s)),
         }
     }
+
+    fn parse_localized_summary(lang: &'a str) -> ParseStatus<'a> {
+        let is_lang = !lang.is_empty()
+            && lang
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_lang {
+            return ParseStatus::Invalid;
+        }
+        ParseStatus::Correct(Format::LocalizedSummary(lang))
+    }
+
+    // body of %{?FIELD=VALUE:THEN|ELSE}, without the ?
+    fn parse_conditional(body: &'a str) -> ParseStatus<'a> {
+        let Some((condition, branches)) = body.split_once(":") else {
+            return ParseStatus::Invalid;
+        };
+        let Some((then, otherwise)) = branches.split_once("|") else {
+            return ParseStatus::Invalid;
+        };
+        let condition = match condition.split_once("=") {
+            Some(("r", "Explicit")) => Condition::Reason(PackageReason::Explicit),
+            Some(("r", "Depend")) => Condition::Reason(PackageReason::Depend),
+            _ => return ParseStatus::Invalid,
+        };
+
+        ParseStatus::Correct(Format::Conditional {
+            condition,
+            then,
+            otherwise,
+        })
+    }
+
+    fn parse_date(field: DateField, pattern: &'a str) -> ParseStatus<'a> {
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c == '%'
+                && !chars
+                    .next()
+                    .is_some_and(|c| STRFTIME_CONVERSIONS.contains(c))
+            {
+                return ParseStatus::Invalid;
+            }
+        }
+        if pattern.is_empty() || pattern.contains('\0') {
+            return ParseStatus::Invalid;
+        }
+
+        ParseStatus::Correct(Format::Date { field, pattern })
+    }
+
+    // body of %{KEY:MODIFIER...}, where KEY itself may contain colons
+    fn parse_modified(body: &'a str) -> ParseStatus<'a> {
+        let base = body
+            .match_indices(':')
+            .map(|(i, _)| i)
+            .chain([body.len()])
+            .rev()
+            .find_map(|i| {
+                TOKENS
+                    .iter()
+                    .find(|t| t.key == &body[..i])
+                    .map(|t| ((t.format)(), &body[i..]))
+            });
+        let Some((format, modifiers)) = base else {
+            return ParseStatus::Invalid;
+        };
+
+        modifiers
+            .split(":")
+            .skip(1)
+            .try_fold(format, |format, segment| {
+                let previous = match &format {
+                    Format::Modified(_, modifier) => Some(modifier),
+                    _ => None,
+                };
+                let (rule, modifier) = MODIFIERS.iter().find_map(|rule| {
+                    (rule.parse)(segment, previous).map(|modifier| (rule, modifier))
+                })?;
+                match format {
+                    Format::Modified(inner, _) if rule.replaces => {
+                        Some(Format::Modified(inner, modifier))
+                    }
+                    format => Some(Format::Modified(Box::new(format), modifier)),
+                }
+            })
+            .map_or(ParseStatus::Invalid, ParseStatus::Correct)
+    }
+
+    fn render<P: PackageFields>(
+        &self,
+        pkg: &P,
+        depth: usize,
+        context: &RenderContext<P>,
+        output: &mut String,
+    ) {
+        match self {
+            Format::Text(s) => output.push_str(s),
+            Format::Name => output.push_str(pkg.name()),
+            Format::Summary => output.push_str(pkg.desc().unwrap_or("")),
+            Format::LocalizedSummary(lang) => {
+                let desc = pkg.localized_desc(lang).or(pkg.desc());
+                output.push_str(desc.unwrap_or(""));
+            }
+            Format::Reason => output.push_str(reason_name(pkg.reason())),
+            Format::ReasonColor => {
+                let reason = reason_name(pkg.reason());
+                match pkg.reason() {
+                    PackageReason::Explicit if context.color => {
+                        output.push_str(&format!("\x1b[32m{}\x1b[m", reason))
+                    }
+                    PackageReason::Depend if context.color => {
+                        output.push_str(&format!("\x1b[90m{}\x1b[m", reason))
+                    }
+                    _ => output.push_str(reason),
+                }
+            }
+            Format::ReasonCount => {
+                let (explicit, depend) = context.reason_counts();
+                match pkg.reason() {
+                    PackageReason::Explicit => output.push_str(&explicit.to_string()),
+                    PackageReason::Depend => output.push_str(&depend.to_string()),
+                }
+            }
+            // the package is looked up by name, as it may come from another db than the local one
+            Format::InstalledReason => {
+                output.push_str(installed_reason(context.lookup.local_reason(pkg.name())))
+            }
+            Format::Version => output.push_str(pkg.version()),
+            Format::Date { field, pattern } => {
+                if let Some(date) = field.timestamp(pkg) {
+                    output.push_str(&format_date(date, pattern));
+                }
+            }
+            Format::Age => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs() as i64);
+                if let Some(date) = pkg.install_date() {
+                    output.push_str(&human_age(now - date));
+                }
+            }
+            Format::Url => output.push_str(pkg.url().unwrap_or("")),
+            Format::UrlHost => output.push_str(pkg.url().map_or("", url_host)),
+            Format::ShortHash => output.push_str(&short_hash(pkg.name(), pkg.version())),
+            Format::Arch => output.push_str(pkg.arch().unwrap_or("any")),
+            // the same wording pacman uses
+            Format::Packager => output.push_str(pkg.packager().unwrap_or("Unknown Packager")),
+            Format::Size => output.push_str(&human_size(pkg.isize())),
+            Format::SizeBytes => output.push_str(&pkg.isize().to_string()),
+            Format::SizePercent => {
+                if let Some(pct) = size_percent(pkg.isize(), context.total_size) {
+                    output.push_str(&format!("{:.2}%", pct));
+                }
+            }
+            Format::Depends => output.push_str(&pkg.depends().join(" ")),
+            Format::SizeBar => {
+                let bar = size_bar(pkg.isize(), context.max_size);
+                if context.color && !bar.is_empty() {
+                    output.push_str(&format!("\x1b[36m{}\x1b[m", bar));
+                } else {
+                    output.push_str(&bar);
+                }
+            }
+            Format::DepsTree => {
+                let depends = context.lookup.all_depends(pkg.name());
+                if !depends.is_empty() {
+                    output.push_str(&format!("({})", depends.join(", ")));
+                }
+            }
+            // renders empty for foreign packages, which no synced repo has
+            Format::Compression => {
+                let download_size = context.lookup.download_size(pkg.name()).unwrap_or(0);
+                if let Some(pct) = compression_ratio(download_size, pkg.isize()) {
+                    output.push_str(&format!("{:.2}%", pct));
+                }
+            }
+            Format::RequiredBy => output.push_str(&pkg.required_by().join(", ")),
+            Format::Multilib => {
+                let is_32bit = matches!(pkg.arch(), Some("i486" | "i686" | "pentium4"));
+                if pkg.name().starts_with("lib32-") || is_32bit {
+                    output.push_str("lib32");
+                }
+            }
+            Format::NameLink if context.color => {
+                let url = match pkg.url() {
+                    Some(url) => url.to_string(),
+                    None => format!("https://archlinux.org/packages/?name={}", pkg.name()),
+                };
+                output.push_str(&hyperlink(&url, pkg.name()));
+            }
+            Format::NameLink => output.push_str(pkg.name()),
+            Format::VersionCmp(other) => {
+                let order = context.lookup.vercmp(pkg.version(), other);
+                output.push_str(version_relation(order));
+            }
+            Format::Depth => output.push_str(&depth.to_string()),
+            Format::License => output.push_str(&pkg.licenses().join(", ")),
+            Format::LicenseSpdx => {
+                let licenses: Vec<_> = pkg.licenses().into_iter().map(spdx_license).collect();
+                output.push_str(&licenses.join(", "));
+            }
+            Format::RequiredByCount => output.push_str(&pkg.required_by().len().to_string()),
+            Format::RequiredByTransitive => {
+                let count = context.lookup.required_by_transitive(pkg.name());
+                output.push_str(&count.to_string());
+            }
+            Format::Groups => output.push_str(&pkg.groups().join(", ")),
+            Format::GroupsCount => output.push_str(&pkg.groups().len().to_string()),
+            Format::GroupsFirst => output.push_str(pkg.groups().first().copied().unwrap_or("")),
+            Format::Backup => output.push_str(&pkg.backup().join(", ")),
+            Format::OptionalReasons => output.push_str(&pkg.optdepend_reasons().join(", ")),
+            Format::ProvidesCount => output.push_str(&pkg.provides_count().to_string()),
+            Format::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                if condition.test(pkg) {
+                    output.push_str(then)
+                } else {
+                    output.push_str(otherwise)
+                }
+            }
+            Format::Modified(inner, modifier) => {
+                let mut value = String::new();
+                inner.render(pkg, depth, context, &mut value);
+                let value = match modifier {
+                    Modifier::LeftJustify => {
+                        Modifier::AlignLeft(context.widest(inner, depth)).apply(value)
+                    }
+                    Modifier::RightJustify => {
+                        Modifier::AlignRight(context.widest(inner, depth)).apply(value)
+                    }
+                    modifier => modifier.apply(value),
+                };
+                output.push_str(&value);
+            }
+        }
+    }
+}
+
+// a json string literal, with the characters json doesn't allow as is escaped
+pub fn json_string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c if c < ' ' => literal.push_str(&format!("\\u{:04x}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn reason_name(reason: PackageReason) -> &'static str {
+    match reason {
+        PackageReason::Explicit => "Explicit",
+        PackageReason::Depend => "Depend",
+    }
+}
+
+// the packages requiring this one are only included for --required-by
+pub fn package_json<P: PackageFields>(pkg: &P, required_by: Option<&[&P]>) -> String {
+    let mut fields = vec![
+        format!("\"name\":{}", json_string(pkg.name())),
+        format!("\"version\":{}", json_string(pkg.version())),
+        format!("\"reason\":{}", json_string(reason_name(pkg.reason()))),
+        format!(
+            "\"description\":{}",
+            pkg.desc().map_or("null".to_string(), json_string)
+        ),
+    ];
+    if let Some(required_by) = required_by {
+        let dependents: Vec<_> = required_by
+            .iter()
+            .map(|dep| {
+                format!(
+                    "{{\"name\":{},\"reason\":{}}}",
+                    json_string(dep.name()),
+                    json_string(reason_name(dep.reason()))
+                )
+            })
+            .collect();
+        fields.push(format!("\"required_by\":[{}]", dependents.join(",")));
+    }
+
+    format!("{{{}}}", fields.join(","))
+}
+
+// what the tokens read of a package, so they can also be rendered for packages made up in tests
+pub trait PackageFields {
+    fn name(&self) -> &str;
+    fn version(&self) -> &str;
+    fn desc(&self) -> Option<&str>;
+    // the description in the language, None where there is no translation
+    fn localized_desc(&self, lang: &str) -> Option<&str>;
+    fn reason(&self) -> PackageReason;
+    fn url(&self) -> Option<&str>;
+    fn arch(&self) -> Option<&str>;
+    fn packager(&self) -> Option<&str>;
+    fn isize(&self) -> i64;
+    fn build_date(&self) -> Option<i64>;
+    fn install_date(&self) -> Option<i64>;
+    // with their version constraints
+    fn depends(&self) -> Vec<String>;
+    fn required_by(&self) -> Vec<String>;
+    fn optional_for(&self) -> Vec<String>;
+    fn licenses(&self) -> Vec<&str>;
+    fn groups(&self) -> Vec<&str>;
+    fn backup(&self) -> Vec<&str>;
+    // the optional dependencies without a reason are left out
+    fn optdepend_reasons(&self) -> Vec<&str>;
+    fn provides_count(&self) -> usize;
+}
+
+impl PackageFields for Package {
+    fn name(&self) -> &str {
+        Pkg::name(self)
+    }
+
+    fn version(&self) -> &str {
+        Pkg::version(self).as_str()
+    }
+
+    fn desc(&self) -> Option<&str> {
+        Pkg::desc(self)
+    }
+
+    // the local db only keeps the description the package was built with
+    fn localized_desc(&self, _lang: &str) -> Option<&str> {
+        None
+    }
+
+    fn reason(&self) -> PackageReason {
+        Pkg::reason(self)
+    }
+
+    fn url(&self) -> Option<&str> {
+        Pkg::url(self)
+    }
+
+    fn arch(&self) -> Option<&str> {
+        Pkg::arch(self)
+    }
+
+    fn packager(&self) -> Option<&str> {
+        Pkg::packager(self)
+    }
+
+    fn isize(&self) -> i64 {
+        Pkg::isize(self)
+    }
+
+    // alpm reports a missing build date as 0
+    fn build_date(&self) -> Option<i64> {
+        Some(Pkg::build_date(self)).filter(|date| *date > 0)
+    }
+
+    fn install_date(&self) -> Option<i64> {
+        Pkg::install_date(self)
+    }
+
+    fn depends(&self) -> Vec<String> {
+        Pkg::depends(self)
+            .iter()
+            .map(|dep| dep.to_string())
+            .collect()
+    }
+
+    fn required_by(&self) -> Vec<String> {
+        Pkg::required_by(self)
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    fn optional_for(&self) -> Vec<String> {
+        Pkg::optional_for(self)
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    fn licenses(&self) -> Vec<&str> {
+        Pkg::licenses(self).iter().collect()
+    }
+
+    fn groups(&self) -> Vec<&str> {
+        Pkg::groups(self).iter().collect()
+    }
+
+    fn backup(&self) -> Vec<&str> {
+        Pkg::backup(self).iter().map(|b| b.name()).collect()
+    }
+
+    fn optdepend_reasons(&self) -> Vec<&str> {
+        Pkg::optdepends(self)
+            .iter()
+            .filter_map(|d| d.desc())
+            .collect()
+    }
+
+    fn provides_count(&self) -> usize {
+        Pkg::provides(self).len()
+    }
+}
+
+// what the tokens look up in the dbs, beyond the package they are rendered for
+pub trait Lookup {
+    // how the package of that name is installed, if it is
+    fn local_reason(&self, name: &str) -> Option<PackageReason>;
+    // the local db doesn't record the download size, so it comes from the first synced repo that
+    // has the package
+    fn download_size(&self, name: &str) -> Option<i64>;
+    // the reasons of every installed package
+    fn local_reasons(&self) -> Vec<PackageReason>;
+    // pacman's version ordering
+    fn vercmp(&self, a: &str, b: &str) -> Ordering;
+    // these walk the local db, which is costly, so only the tokens needing them ask
+    fn all_depends(&self, name: &str) -> Vec<String>;
+    fn required_by_transitive(&self, name: &str) -> usize;
+}
+
+impl Lookup for Alpm {
+    fn local_reason(&self, name: &str) -> Option<PackageReason> {
+        self.localdb().pkg(name).ok().map(|pkg| pkg.reason())
+    }
+
+    fn download_size(&self, name: &str) -> Option<i64> {
+        self.syncdbs()
+            .into_iter()
+            .find_map(|db| db.pkg(name).ok())
+            .map(|pkg| pkg.size())
+    }
+
+    fn local_reasons(&self) -> Vec<PackageReason> {
+        self.localdb()
+            .pkgs()
+            .iter()
+            .map(|pkg| pkg.reason())
+            .collect()
+    }
+
+    fn vercmp(&self, a: &str, b: &str) -> Ordering {
+        alpm::vercmp(a, b)
+    }
+
+    fn all_depends(&self, name: &str) -> Vec<String> {
+        let local = self.localdb();
+        local.pkg(name).map_or(Vec::new(), |pkg| {
+            all_depends(local, pkg)
+                .into_iter()
+                .map(|dep| dep.name().to_string())
+                .collect()
+        })
+    }
+
+    fn required_by_transitive(&self, name: &str) -> usize {
+        let local = self.localdb();
+        local
+            .pkg(name)
+            .map_or(0, |pkg| count_required_by(local, pkg))
+    }
+}
+
+pub struct RenderContext<'p, P> {
+    lookup: &'p dyn Lookup,
+    pkgs: &'p [&'p P],
+    total_size: i64,
+    max_size: i64,
+    color: bool,
+    // explicit and dependency packages in the whole local db, counted on first use
+    reason_counts: OnceCell<(usize, usize)>,
+    // the widest rendering of each justified token, keyed by the address of the token which
+    // stays put as long as the compiled format lives
+    widths: RefCell<HashMap<usize, usize>>,
+}
+
+impl<'p, P: PackageFields> RenderContext<'p, P> {
+    pub fn new(lookup: &'p dyn Lookup, pkgs: &'p [&'p P], color: bool) -> RenderContext<'p, P> {
+        RenderContext {
+            lookup,
+            pkgs,
+            total_size: pkgs.iter().map(|pkg| pkg.isize()).sum(),
+            max_size: pkgs.iter().map(|pkg| pkg.isize()).max().unwrap_or(0),
+            color,
+            reason_counts: OnceCell::new(),
+            widths: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // renders the token for every package, so only once per token
+    fn widest(&self, format: &Format, depth: usize) -> usize {
+        let key = format as *const Format as usize;
+        let cached = self.widths.borrow().get(&key).copied();
+        if let Some(width) = cached {
+            return width;
+        }
+
+        let width = self
+            .pkgs
+            .iter()
+            .map(|pkg| {
+                let mut value = String::new();
+                format.render(*pkg, depth, self, &mut value);
+                value.chars().count()
+            })
+            .max()
+            .unwrap_or(0);
+        self.widths.borrow_mut().insert(key, width);
+        width
+    }
+
+    fn reason_counts(&self) -> (usize, usize) {
+        *self.reason_counts.get_or_init(|| {
+            let reasons = self.lookup.local_reasons();
+            let explicit = reasons
+                .iter()
+                .filter(|reason| **reason == PackageReason::Explicit)
+                .count();
+            (explicit, reasons.len() - explicit)
+        })
+    }
+}
+
+// the offending token and its byte offset in the format
+#[derive(Debug)]
+pub enum FormatError {
+    InvalidToken(String, usize),
+    Unterminated(String, usize),
+}
+
+impl error::Error for FormatError {}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        use FormatError::*;
+        match self {
+            InvalidToken(token, offset) => write!(f, "unknown token '{}' at {}", token, offset),
+            Unterminated(token, offset) => {
+                write!(f, "unterminated token '{}' at {}", token, offset)
+            }
+        }
+    }
 }
 
 pub struct CompiledFormat<'a>(Vec<Format<'a>>);
 
 impl<'a> CompiledFormat<'a> {
-    pub fn compile(text: &'a str) -> Option<Self> {
+    pub fn compile(text: &'a str) -> Result<Self, FormatError> {
         let mut start = 0;
         let mut end = 0;
         let mut format_parts = Vec::new();
@@ -50,36 +1344,64 @@ impl<'a> CompiledFormat<'a> {
             let chunk = &text[start..end];
             match Format::parse_token_slice(chunk) {
                 ParseStatus::NeedMore => {
-                    end += 1;
+                    end = next_boundary(text, end);
                     if end > text.len() {
-                        return None;
+                        return Err(FormatError::Unterminated(chunk.to_string(), start));
                     }
                 }
                 ParseStatus::Invalid => {
-                    return None;
+                    return Err(FormatError::InvalidToken(chunk.to_string(), start));
                 }
                 ParseStatus::Correct(form) => {
                     format_parts.push(form);
                     start = end;
-                    end += 1;
+                    end = next_boundary(text, end);
                 }
             }
         }
-        Some(CompiledFormat(format_parts))
+
+        // width-env tokens share the line with the literal text around them
+        let fixed: usize = format_parts
+            .iter()
+            .map(|part| match part {
+                Format::Text(s) => s.chars().count(),
+                _ => 0,
+            })
+            .sum();
+        format_parts
+            .iter_mut()
+            .for_each(|part| part.shrink_fit(fixed));
+        Ok(CompiledFormat(format_parts))
+    }
+
+    // whether a token reads the sync dbs, which then have to be registered first
+    pub fn needs_sync_dbs(&self) -> bool {
+        self.0
+            .iter()
+            .any(|part| matches!(part.base(), Format::Compression))
     }
 
-    pub fn display(&self, pkg: &Pkg) -> String {
+    // whether a token colors its own output, so the line it is on mustn't be colored as a whole
+    pub fn uses_color(&self) -> bool {
+        self.0
+            .iter()
+            .any(|part| matches!(part.base(), Format::ReasonColor | Format::SizeBar))
+    }
+
+    pub fn display<P: PackageFields>(&self, pkg: &P, context: &RenderContext<P>) -> String {
+        self.display_at_depth(pkg, 0, context)
+    }
+
+    pub fn display_at_depth<P: PackageFields>(
+        &self,
+        pkg: &P,
+        depth: usize,
+        context: &RenderContext<P>,
+    ) -> String {
         let mut output = String::new();
-        self.0.iter().for_each(|part| match part {
-            Format::Text(s) => output.push_str(s),
-            Format::Name => output.push_str(pkg.name()),
-            Format::Summary => output.push_str(pkg.desc().unwrap_or("")),
-            Format::Reason => match pkg.reason() {
-                PackageReason::Explicit => output.push_str("Explicit"),
-                PackageReason::Depend => output.push_str("Depend"),
-            },
-            Format::Version => output.push_str(pkg.version().as_str()),
-        });
+        self.0
+            .iter()
+            .for_each(|part| part.render(pkg, depth, context, &mut output));
         output
     }
 }
@@ -89,3 +1411,1147 @@ impl Default for CompiledFormat<'static> {
         CompiledFormat(vec![Format::Name])
     }
 }
+
+pub fn print_format_tokens() -> String {
+    let rows: Vec<_> = TOKENS
+        .iter()
+        .map(|token| (token.spelling(), token.comment))
+        .chain(
+            ESCAPES
+                .iter()
+                .map(|(spelling, _, comment)| (spelling.to_string(), *comment)),
+        )
+        .chain(
+            ARGUMENT_TOKENS
+                .iter()
+                .map(|token| (token.spelling(), token.comment)),
+        )
+        .chain(
+            MODIFIERS
+                .iter()
+                .map(|rule| (format!("%{{KEY{}}}", rule.suffix), rule.comment)),
+        )
+        .collect();
+    let max_width = rows
+        .iter()
+        .map(|(spelling, _)| spelling.len())
+        .max()
+        .unwrap_or(0);
+
+    let lines: Vec<_> = rows
+        .into_iter()
+        .map(|(spelling, comment)| format!("\t{spelling:max_width$}\t{comment}"))
+        .collect();
+
+    lines.join("\n")
+}
+
+enum Sink {
+    // holds every line until finish, for when the lines must be checked before printing
+    Buffered(Vec<String>),
+    Streaming,
+    // only the number of lines is printed, for --count
+    Counting(usize),
+}
+
+#[derive(Debug)]
+pub enum PrintError {
+    Io(io::Error),
+    // a line with whitespace, which --xargs-safe refuses to print
+    UnsafeForXargs(String),
+}
+
+impl From<io::Error> for PrintError {
+    fn from(err: io::Error) -> PrintError {
+        PrintError::Io(err)
+    }
+}
+
+pub struct Printer<W: Write = BufWriter<StdoutLock<'static>>> {
+    sink: Sink,
+    out: W,
+    // ends every line, a newline unless --print0
+    terminator: char,
+    // xargs -0 splits on nothing but the null byte, so this is only checked without --print0
+    xargs_safe: bool,
+}
+
+impl Printer {
+    pub fn new(streaming: bool, print0: bool, xargs_safe: bool) -> Printer {
+        let sink = if streaming {
+            Sink::Streaming
+        } else {
+            Sink::Buffered(Vec::new())
+        };
+        let out = BufWriter::new(io::stdout().lock());
+        Printer::with_sink(sink, print0, xargs_safe, out)
+    }
+
+    pub fn counting(print0: bool) -> Printer {
+        let out = BufWriter::new(io::stdout().lock());
+        Printer::with_sink(Sink::Counting(0), print0, false, out)
+    }
+}
+
+impl<W: Write> Printer<W> {
+    fn with_sink(sink: Sink, print0: bool, xargs_safe: bool, out: W) -> Printer<W> {
+        let terminator = if print0 { '\0' } else { '\n' };
+        Printer {
+            sink,
+            out,
+            terminator,
+            xargs_safe: xargs_safe && !print0,
+        }
+    }
+
+    pub fn push(&mut self, line: String) -> Result<(), PrintError> {
+        if self.xargs_safe && line.contains(char::is_whitespace) {
+            return Err(PrintError::UnsafeForXargs(line));
+        }
+        match &mut self.sink {
+            Sink::Buffered(lines) => {
+                lines.push(line);
+                Ok(())
+            }
+            Sink::Streaming => Ok(write!(self.out, "{}{}", line, self.terminator)?),
+            Sink::Counting(count) => {
+                *count += 1;
+                Ok(())
+            }
+        }
+    }
+
+    // only needed when lines must show up before the next one is ready
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.sink {
+            Sink::Streaming => self.out.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match &self.sink {
+            Sink::Buffered(lines) => lines
+                .iter()
+                .try_for_each(|line| write!(self.out, "{}{}", line, self.terminator))?,
+            Sink::Streaming => {}
+            Sink::Counting(count) => write!(self.out, "{}{}", count, self.terminator)?,
+        }
+        self.out.flush()
+    }
+}
+
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+// progress on stderr while a slow walk streams its results, only drawn on a terminal
+pub struct Spinner {
+    enabled: bool,
+    found: usize,
+}
+
+impl Spinner {
+    pub fn on_terminal() -> Spinner {
+        Spinner {
+            enabled: io::stderr().is_terminal(),
+            found: 0,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.enabled {
+            self.found += 1;
+            let frame = SPINNER_FRAMES[self.found % SPINNER_FRAMES.len()];
+            eprint!("\r{} {} found", frame, self.found);
+        }
+    }
+
+    // must be called before printing anything, or the line ends up after the spinner
+    pub fn clear(&self) {
+        if self.enabled && self.found > 0 {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+// packages and dbs made up in place, to test tokens and listings without alpm
+#[cfg(test)]
+pub mod testing {
+    use super::{Lookup, PackageFields};
+    use alpm::PackageReason;
+    use std::cmp::Ordering;
+
+    pub struct TestPackage {
+        pub name: &'static str,
+        pub version: &'static str,
+        pub desc: Option<&'static str>,
+        // (language, description)
+        pub localized_descs: Vec<(&'static str, &'static str)>,
+        pub reason: PackageReason,
+        pub url: Option<&'static str>,
+        pub arch: Option<&'static str>,
+        pub packager: Option<&'static str>,
+        pub isize: i64,
+        pub build_date: Option<i64>,
+        pub install_date: Option<i64>,
+        pub depends: Vec<&'static str>,
+        pub required_by: Vec<&'static str>,
+        pub optional_for: Vec<&'static str>,
+        pub licenses: Vec<&'static str>,
+        pub groups: Vec<&'static str>,
+        pub backup: Vec<&'static str>,
+        pub optdepend_reasons: Vec<&'static str>,
+        pub provides_count: usize,
+    }
+
+    impl TestPackage {
+        // explicitly installed, with a version and nothing else
+        pub fn new(name: &'static str) -> TestPackage {
+            TestPackage {
+                name,
+                version: "1.0-1",
+                desc: None,
+                localized_descs: Vec::new(),
+                reason: PackageReason::Explicit,
+                url: None,
+                arch: None,
+                packager: None,
+                isize: 0,
+                build_date: None,
+                install_date: None,
+                depends: Vec::new(),
+                required_by: Vec::new(),
+                optional_for: Vec::new(),
+                licenses: Vec::new(),
+                groups: Vec::new(),
+                backup: Vec::new(),
+                optdepend_reasons: Vec::new(),
+                provides_count: 0,
+            }
+        }
+
+        pub fn depend(name: &'static str) -> TestPackage {
+            TestPackage {
+                reason: PackageReason::Depend,
+                ..TestPackage::new(name)
+            }
+        }
+    }
+
+    impl PackageFields for TestPackage {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn version(&self) -> &str {
+            self.version
+        }
+
+        fn desc(&self) -> Option<&str> {
+            self.desc
+        }
+
+        fn localized_desc(&self, lang: &str) -> Option<&str> {
+            find(&self.localized_descs, lang)
+        }
+
+        fn reason(&self) -> PackageReason {
+            self.reason
+        }
+
+        fn url(&self) -> Option<&str> {
+            self.url
+        }
+
+        fn arch(&self) -> Option<&str> {
+            self.arch
+        }
+
+        fn packager(&self) -> Option<&str> {
+            self.packager
+        }
+
+        fn isize(&self) -> i64 {
+            self.isize
+        }
+
+        fn build_date(&self) -> Option<i64> {
+            self.build_date
+        }
+
+        fn install_date(&self) -> Option<i64> {
+            self.install_date
+        }
+
+        fn depends(&self) -> Vec<String> {
+            self.depends.iter().map(|s| s.to_string()).collect()
+        }
+
+        fn required_by(&self) -> Vec<String> {
+            self.required_by.iter().map(|s| s.to_string()).collect()
+        }
+
+        fn optional_for(&self) -> Vec<String> {
+            self.optional_for.iter().map(|s| s.to_string()).collect()
+        }
+
+        fn licenses(&self) -> Vec<&str> {
+            self.licenses.clone()
+        }
+
+        fn groups(&self) -> Vec<&str> {
+            self.groups.clone()
+        }
+
+        fn backup(&self) -> Vec<&str> {
+            self.backup.clone()
+        }
+
+        fn optdepend_reasons(&self) -> Vec<&str> {
+            self.optdepend_reasons.clone()
+        }
+
+        fn provides_count(&self) -> usize {
+            self.provides_count
+        }
+    }
+
+    // answers by name from the lists, anything not listed is unknown
+    #[derive(Default)]
+    pub struct TestLookup {
+        pub local: Vec<(&'static str, PackageReason)>,
+        pub download_sizes: Vec<(&'static str, i64)>,
+        pub all_depends: Vec<(&'static str, Vec<&'static str>)>,
+        pub required_by_transitive: Vec<(&'static str, usize)>,
+    }
+
+    fn find<T: Clone>(entries: &[(&str, T)], name: &str) -> Option<T> {
+        entries
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    impl Lookup for TestLookup {
+        fn local_reason(&self, name: &str) -> Option<PackageReason> {
+            find(&self.local, name)
+        }
+
+        fn download_size(&self, name: &str) -> Option<i64> {
+            find(&self.download_sizes, name)
+        }
+
+        fn local_reasons(&self) -> Vec<PackageReason> {
+            self.local.iter().map(|(_, reason)| *reason).collect()
+        }
+
+        // plain string order, enough for versions differing in one digit
+        fn vercmp(&self, a: &str, b: &str) -> Ordering {
+            a.cmp(b)
+        }
+
+        fn all_depends(&self, name: &str) -> Vec<String> {
+            find(&self.all_depends, name)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect()
+        }
+
+        fn required_by_transitive(&self, name: &str) -> usize {
+            find(&self.required_by_transitive, name).unwrap_or(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{TestLookup, TestPackage};
+    use super::*;
+
+    fn render_all(
+        text: &str,
+        pkgs: &[&TestPackage],
+        lookup: &TestLookup,
+        color: bool,
+    ) -> Vec<String> {
+        let format = CompiledFormat::compile(text).unwrap();
+        let context = RenderContext::new(lookup, pkgs, color);
+        pkgs.iter()
+            .map(|pkg| format.display(*pkg, &context))
+            .collect()
+    }
+
+    fn render(text: &str, pkg: &TestPackage) -> String {
+        render_all(text, &[pkg], &TestLookup::default(), false).remove(0)
+    }
+
+    #[test]
+    fn human_size_unit_boundaries() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(1048524), "1023.9 KiB");
+        assert_eq!(human_size(1048575), "1.0 MiB");
+        assert_eq!(human_size(1048576), "1.0 MiB");
+        assert_eq!(human_size(1073741823), "1.0 GiB");
+        assert_eq!(human_size(1099511627776), "1024.0 GiB");
+    }
+
+    #[test]
+    fn installed_reason_of_installed_and_missing_packages() {
+        assert_eq!(installed_reason(Some(PackageReason::Explicit)), "explicit");
+        assert_eq!(installed_reason(Some(PackageReason::Depend)), "dependency");
+        assert_eq!(installed_reason(None), "not-installed");
+    }
+
+    // the spellings --list-formats shows for tokens with arguments and modifiers, filled in
+    #[test]
+    fn listed_spellings_compile() {
+        let spellings = ARGUMENT_TOKENS.iter().map(|token| token.spelling()).chain(
+            MODIFIERS
+                .iter()
+                .map(|rule| format!("%{{KEY{}}}", rule.suffix)),
+        );
+        for spelling in spellings {
+            let filled = spelling
+                .replace("KEY", "n")
+                .replace("WIDTH", "10")
+                .replace("STR", "~")
+                .replace("VERSION", "1.0")
+                .replace("VALUE", "Explicit")
+                .replace("FORMAT", "%Y")
+                .replace("LANG", "de");
+            assert!(CompiledFormat::compile(&filled).is_ok(), "{}", filled);
+        }
+    }
+
+    #[test]
+    fn modifiers_changing_a_width_need_one() {
+        for text in [
+            "%{n:10:wrap}",
+            "%{n:10:trunc:ellipsis}",
+            "%{n:10:ellipsis=..}",
+        ] {
+            assert!(CompiledFormat::compile(text).is_ok(), "{}", text);
+        }
+        for text in [
+            "%{n:wrap}",
+            "%{n:ellipsis}",
+            "%{n:10:ellipsis:wrap}",
+            "%{n:10:ellipsis-}",
+        ] {
+            assert!(CompiledFormat::compile(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn xargs_safe_rejects_whitespace_unless_null_terminated() {
+        let mut printer = Printer::new(false, false, true);
+        assert!(printer.push("linux".to_string()).is_ok());
+        assert!(matches!(
+            printer.push("linux lts".to_string()),
+            Err(PrintError::UnsafeForXargs(line)) if line == "linux lts"
+        ));
+        let mut printer = Printer::new(false, true, true);
+        assert!(printer.push("linux lts".to_string()).is_ok());
+    }
+
+    #[test]
+    fn counting_sink_counts_pushed_packages() {
+        let mut printer = Printer::counting(false);
+        for name in ["linux", "linux-firmware", "linux"] {
+            printer.push(name.to_string()).unwrap();
+        }
+        assert!(matches!(printer.sink, Sink::Counting(3)));
+    }
+
+    #[test]
+    fn sync_dbs_are_needed_below_any_modifiers() {
+        let format = CompiledFormat::compile("%n %{compression:10:upper}").unwrap();
+        assert!(format.needs_sync_dbs());
+        assert!(!CompiledFormat::compile("%{n:10:upper}")
+            .unwrap()
+            .needs_sync_dbs());
+    }
+
+    #[test]
+    fn colored_tokens_are_found_below_modifiers() {
+        for text in ["%n %{reason:color:10}", "%{size:bar}"] {
+            assert!(
+                CompiledFormat::compile(text).unwrap().uses_color(),
+                "{}",
+                text
+            );
+        }
+        assert!(!CompiledFormat::compile("%n %r").unwrap().uses_color());
+    }
+
+    #[test]
+    fn size_percentages_add_up_to_the_total() {
+        let sizes = [1024, 3 * 1024 * 1024, 7, 500_000];
+        let total_size = sizes.iter().sum();
+        let sum: f64 = sizes
+            .iter()
+            .filter_map(|size| size_percent(*size, total_size))
+            .sum();
+        assert!((sum - 100.0).abs() < 1e-9, "{}", sum);
+        assert_eq!(size_percent(10, 0), None);
+    }
+
+    fn single_part(text: &str) -> Format<'_> {
+        let CompiledFormat(mut parts) = CompiledFormat::compile(text).unwrap();
+        assert_eq!(parts.len(), 1, "{}", text);
+        parts.remove(0)
+    }
+
+    // the short spelling exists for one letter keys only, the braced one for every key
+    #[test]
+    fn every_token_compiles_in_its_spellings() {
+        for token in &TOKENS {
+            let expected = std::mem::discriminant(&(token.format)());
+            for spelling in token.spelling().split('|') {
+                assert_eq!(
+                    std::mem::discriminant(&single_part(spelling)),
+                    expected,
+                    "{}",
+                    spelling
+                );
+            }
+        }
+        for (escape, text, _) in ESCAPES {
+            assert!(matches!(single_part(escape), Format::Text(t) if t == text));
+        }
+    }
+
+    #[test]
+    fn licenses_map_to_spdx_identifiers() {
+        assert_eq!(spdx_license("GPL2"), "GPL-2.0-only");
+        assert_eq!(spdx_license("BSD"), "BSD-3-Clause");
+        assert_eq!(spdx_license("custom"), "LicenseRef-custom");
+        assert_eq!(spdx_license("custom:Unicode"), "LicenseRef-Unicode");
+        // already an identifier, or nothing known to map it to
+        assert_eq!(spdx_license("MIT"), "MIT");
+        assert_eq!(spdx_license("Apache-2.0"), "Apache-2.0");
+    }
+
+    #[test]
+    fn modified_keys_may_contain_colons() {
+        assert!(matches!(
+            Format::parse_modified("n:upper"),
+            ParseStatus::Correct(Format::Modified(inner, Modifier::Upper))
+                if matches!(*inner, Format::Name)
+        ));
+        assert!(matches!(
+            Format::parse_modified("size:pct:lower"),
+            ParseStatus::Correct(Format::Modified(inner, Modifier::Lower))
+                if matches!(*inner, Format::SizePercent)
+        ));
+        assert!(matches!(
+            Format::parse_modified("n:shout"),
+            ParseStatus::Invalid
+        ));
+        assert!(matches!(
+            Format::parse_modified("nope:upper"),
+            ParseStatus::Invalid
+        ));
+    }
+
+    #[test]
+    fn case_modifiers() {
+        assert_eq!(Modifier::Lower.apply("Linux-LTS".to_string()), "linux-lts");
+        assert_eq!(Modifier::Upper.apply("Linux-LTS".to_string()), "LINUX-LTS");
+    }
+
+    // literal text compiles to a part per character
+    #[test]
+    fn license_token_compiles_between_text() {
+        let CompiledFormat(parts) = CompiledFormat::compile("%n: %l").unwrap();
+        assert!(matches!(
+            parts.as_slice(),
+            [
+                Format::Name,
+                Format::Text(":"),
+                Format::Text(" "),
+                Format::License
+            ]
+        ));
+    }
+
+    #[test]
+    fn unknown_tokens_report_their_offset() {
+        assert!(matches!(
+            CompiledFormat::compile("%n %q"),
+            Err(FormatError::InvalidToken(token, 3)) if token == "%q"
+        ));
+        assert!(matches!(
+            CompiledFormat::compile("%n %{size"),
+            Err(FormatError::Unterminated(token, 3)) if token == "%{size"
+        ));
+    }
+
+    #[test]
+    fn ages_round_down_to_the_largest_unit() {
+        assert_eq!(human_age(-5), "0 seconds");
+        assert_eq!(human_age(1), "1 second");
+        assert_eq!(human_age(59), "59 seconds");
+        assert_eq!(human_age(60), "1 minute");
+        assert_eq!(human_age(2 * 3600 - 1), "1 hour");
+        assert_eq!(human_age(13 * 86400), "1 week");
+        assert_eq!(human_age(30 * 86400), "1 month");
+        assert_eq!(human_age(364 * 86400), "12 months");
+        assert_eq!(human_age(3 * 365 * 86400), "3 years");
+    }
+
+    #[test]
+    fn size_bars_fill_eighths_of_a_cell() {
+        assert_eq!(size_bar(160, 160), "█".repeat(SIZE_BAR_WIDTH));
+        assert_eq!(size_bar(80, 160), "█".repeat(SIZE_BAR_WIDTH / 2));
+        assert_eq!(size_bar(1, 160), "▏");
+        assert_eq!(size_bar(11, 160), "█▍");
+        assert_eq!(size_bar(0, 160), "");
+        assert_eq!(size_bar(10, 0), "");
+    }
+
+    #[test]
+    fn url_hosts_drop_user_port_and_path() {
+        assert_eq!(url_host("https://www.kernel.org/"), "www.kernel.org");
+        assert_eq!(url_host("https://git@github.com:22/x/y"), "github.com");
+        assert_eq!(url_host("http://example.org?page=1#top"), "example.org");
+        assert_eq!(url_host("ftp://ftp.gnu.org"), "ftp.gnu.org");
+        assert_eq!(url_host("example.org/path"), "example.org/path");
+    }
+
+    // known FNV-1a values, a changed hash would break comparing older listings
+    #[test]
+    fn short_hashes_are_stable() {
+        assert_eq!(short_hash("", ""), "050c5d1f");
+        assert_eq!(short_hash("linux", "6.1-1"), "afca1d54");
+        assert_ne!(short_hash("foo-1", "2"), short_hash("foo", "12"));
+    }
+
+    #[test]
+    fn columns_are_only_read_on_a_terminal() {
+        assert_eq!(width_from(Some("120"), true), 120);
+        assert_eq!(width_from(Some("120"), false), DEFAULT_COLUMNS);
+        assert_eq!(width_from(Some("wide"), true), DEFAULT_COLUMNS);
+        assert_eq!(width_from(None, true), DEFAULT_COLUMNS);
+    }
+
+    // the tests don't print to a terminal, so the width starts out as the default
+    #[test]
+    fn width_env_leaves_room_for_the_text_around_it() {
+        for text in ["%{n:width-env} |", "%{n:width-env:upper} |"] {
+            let CompiledFormat(parts) = CompiledFormat::compile(text).unwrap();
+            let Format::Modified(inner, modifier) = &parts[0] else {
+                panic!("{} is not modified", text);
+            };
+            let width = match (inner.as_ref(), modifier) {
+                (_, Modifier::Fit(width)) | (Format::Modified(_, Modifier::Fit(width)), _) => {
+                    *width
+                }
+                _ => panic!("{} has no width-env", text),
+            };
+            assert_eq!(width, DEFAULT_COLUMNS - 2, "{}", text);
+        }
+    }
+
+    #[test]
+    fn transitive_dependencies_render_inline() {
+        assert!(matches!(single_part("%{deps:tree}"), Format::DepsTree));
+        let lookup = TestLookup {
+            all_depends: vec![("vim", vec!["gpm", "libgcrypt", "glibc"])],
+            ..Default::default()
+        };
+        let vim = TestPackage::new("vim");
+        let nano = TestPackage::new("nano");
+        assert_eq!(
+            render_all("%n %{deps:tree}", &[&vim, &nano], &lookup, false),
+            ["vim (gpm, libgcrypt, glibc)", "nano "]
+        );
+        // unknown to the db
+        assert_eq!(render("%{deps:tree}", &TestPackage::depend("gpm")), "");
+    }
+
+    #[test]
+    fn direct_dependents_render_as_a_list() {
+        assert!(matches!(single_part("%{reqby}"), Format::RequiredBy));
+        let glibc = TestPackage {
+            required_by: vec!["bash", "vim"],
+            ..TestPackage::depend("glibc")
+        };
+        assert_eq!(render("%{reqby}", &glibc), "bash, vim");
+        assert_eq!(render("%{reqby}", &TestPackage::new("vim")), "");
+    }
+
+    #[test]
+    fn multilib_marks_lib32_and_32_bit_packages() {
+        assert!(matches!(single_part("%{arch:multilib}"), Format::Multilib));
+        let lib32 = TestPackage {
+            arch: Some("x86_64"),
+            ..TestPackage::new("lib32-glibc")
+        };
+        let i686 = TestPackage {
+            arch: Some("i686"),
+            ..TestPackage::new("glibc")
+        };
+        let native = TestPackage {
+            arch: Some("x86_64"),
+            ..TestPackage::new("glibc")
+        };
+        assert_eq!(render("%{arch:multilib}", &lib32), "lib32");
+        assert_eq!(render("%{arch:multilib}", &i686), "lib32");
+        assert_eq!(render("%{arch:multilib}", &native), "");
+        assert_eq!(render("%{arch:multilib}", &TestPackage::new("glibc")), "");
+    }
+
+    #[test]
+    fn versions_compare_to_the_given_one() {
+        assert!(matches!(
+            single_part("%{v:cmp=2.0-1}"),
+            Format::VersionCmp("2.0-1")
+        ));
+        let vim = TestPackage {
+            version: "1.5-1",
+            ..TestPackage::new("vim")
+        };
+        assert_eq!(render("%{v:cmp=2.0-1}", &vim), "older");
+        assert_eq!(render("%{v:cmp=1.5-1}", &vim), "equal");
+        assert_eq!(render("%{v:cmp=1.0-1}", &vim), "newer");
+    }
+
+    #[test]
+    fn transitive_dependents_are_counted() {
+        assert!(matches!(
+            single_part("%{reqby:transitive}"),
+            Format::RequiredByTransitive
+        ));
+        let lookup = TestLookup {
+            required_by_transitive: vec![("glibc", 42)],
+            ..Default::default()
+        };
+        let glibc = TestPackage::depend("glibc");
+        let vim = TestPackage::new("vim");
+        assert_eq!(
+            render_all("%{reqby:transitive}", &[&glibc, &vim], &lookup, false),
+            ["42", "0"]
+        );
+    }
+
+    #[test]
+    fn groups_are_counted_and_the_first_picked() {
+        assert!(matches!(
+            single_part("%{groups:count}"),
+            Format::GroupsCount
+        ));
+        assert!(matches!(
+            single_part("%{groups:first}"),
+            Format::GroupsFirst
+        ));
+        let make = TestPackage {
+            groups: vec!["base-devel", "gnu"],
+            ..TestPackage::new("make")
+        };
+        let vim = TestPackage::new("vim");
+        assert_eq!(
+            render("%{groups:count} %{groups:first}", &make),
+            "2 base-devel"
+        );
+        assert_eq!(render("%{groups:count} %{groups:first}", &vim), "0 ");
+    }
+
+    #[test]
+    fn names_link_to_their_url_with_color() {
+        assert!(matches!(single_part("%{n:link}"), Format::NameLink));
+        let vim = TestPackage {
+            url: Some("https://www.vim.org"),
+            ..TestPackage::new("vim")
+        };
+        let nano = TestPackage::new("nano");
+        let lookup = TestLookup::default();
+        assert_eq!(
+            render_all("%{n:link}", &[&vim, &nano], &lookup, true),
+            [
+                "\x1b]8;;https://www.vim.org\x1b\\vim\x1b]8;;\x1b\\",
+                "\x1b]8;;https://archlinux.org/packages/?name=nano\x1b\\nano\x1b]8;;\x1b\\"
+            ]
+        );
+        assert_eq!(render("%{n:link}", &vim), "vim");
+    }
+
+    #[test]
+    fn backup_files_render_as_a_list() {
+        assert!(matches!(single_part("%{backup}"), Format::Backup));
+        let pacman = TestPackage {
+            backup: vec!["etc/pacman.conf", "etc/makepkg.conf"],
+            ..TestPackage::new("pacman")
+        };
+        assert_eq!(
+            render("%{backup}", &pacman),
+            "etc/pacman.conf, etc/makepkg.conf"
+        );
+        assert_eq!(render("%{backup}", &TestPackage::new("vim")), "");
+    }
+
+    #[test]
+    fn both_group_spellings_render_the_groups() {
+        let make = TestPackage {
+            groups: vec!["base-devel", "gnu"],
+            ..TestPackage::new("make")
+        };
+        for text in ["%g", "%{g}", "%{groups}"] {
+            assert!(matches!(single_part(text), Format::Groups), "{}", text);
+            assert_eq!(render(text, &make), "base-devel, gnu", "{}", text);
+        }
+        assert_eq!(render("%g", &TestPackage::new("vim")), "");
+    }
+
+    #[test]
+    fn reasons_are_counted_across_the_local_db() {
+        assert!(matches!(
+            single_part("%{reason:count}"),
+            Format::ReasonCount
+        ));
+        let lookup = TestLookup {
+            local: vec![
+                ("vim", PackageReason::Explicit),
+                ("glibc", PackageReason::Depend),
+                ("gpm", PackageReason::Depend),
+            ],
+            ..Default::default()
+        };
+        let vim = TestPackage::new("vim");
+        let gpm = TestPackage::depend("gpm");
+        assert_eq!(
+            render_all("%{reason:count}", &[&vim, &gpm], &lookup, false),
+            ["1", "2"]
+        );
+    }
+
+    #[test]
+    fn packages_without_an_architecture_are_any() {
+        assert!(matches!(single_part("%a"), Format::Arch));
+        let vim = TestPackage {
+            arch: Some("x86_64"),
+            ..TestPackage::new("vim")
+        };
+        assert_eq!(render("%a", &vim), "x86_64");
+        assert_eq!(render("%a", &TestPackage::new("ca-certificates")), "any");
+    }
+
+    #[test]
+    fn packages_without_a_packager_are_unknown() {
+        assert!(matches!(single_part("%p"), Format::Packager));
+        let vim = TestPackage {
+            packager: Some("Jane Doe <jane@example.org>"),
+            ..TestPackage::new("vim")
+        };
+        assert_eq!(render("%p", &vim), "Jane Doe <jane@example.org>");
+        assert_eq!(render("%p", &TestPackage::new("vim")), "Unknown Packager");
+    }
+
+    #[test]
+    fn dependencies_keep_their_constraints() {
+        assert!(matches!(single_part("%D"), Format::Depends));
+        let vim = TestPackage {
+            depends: vec!["glibc", "ncurses>=6"],
+            ..TestPackage::new("vim")
+        };
+        assert_eq!(render("%D", &vim), "glibc ncurses>=6");
+        assert_eq!(render("%D", &TestPackage::new("filesystem")), "");
+    }
+
+    #[test]
+    fn reasons_are_colored_with_color_only() {
+        assert!(matches!(
+            single_part("%{reason:color}"),
+            Format::ReasonColor
+        ));
+        let vim = TestPackage::new("vim");
+        let gpm = TestPackage::depend("gpm");
+        let lookup = TestLookup::default();
+        assert_eq!(
+            render_all("%{reason:color}", &[&vim, &gpm], &lookup, true),
+            ["\x1b[32mExplicit\x1b[m", "\x1b[90mDepend\x1b[m"]
+        );
+        assert_eq!(
+            render_all("%{reason:color}", &[&vim, &gpm], &lookup, false),
+            ["Explicit", "Depend"]
+        );
+    }
+
+    #[test]
+    fn optional_dependency_reasons_render_as_a_list() {
+        assert!(matches!(
+            single_part("%{o:reasons}"),
+            Format::OptionalReasons
+        ));
+        let vim = TestPackage {
+            optdepend_reasons: vec!["Python 3 language support", "clipboard support"],
+            ..TestPackage::new("vim")
+        };
+        assert_eq!(
+            render("%{o:reasons}", &vim),
+            "Python 3 language support, clipboard support"
+        );
+        assert_eq!(render("%{o:reasons}", &TestPackage::new("nano")), "");
+    }
+
+    #[test]
+    fn justified_tokens_align_to_the_widest_package() {
+        let vi = TestPackage::new("vi");
+        let nano = TestPackage::new("nano");
+        let emacs = TestPackage::new("emacs");
+        let pkgs = [&vi, &nano, &emacs];
+        let lookup = TestLookup::default();
+        assert_eq!(
+            render_all("%{n:ljust}|", &pkgs, &lookup, false),
+            ["vi   |", "nano |", "emacs|"]
+        );
+        assert_eq!(
+            render_all("%{n:rjust}|", &pkgs, &lookup, false),
+            ["   vi|", " nano|", "emacs|"]
+        );
+    }
+
+    #[test]
+    fn hyperlinks_wrap_the_text_in_osc_8() {
+        assert_eq!(
+            hyperlink("https://www.vim.org", "vim"),
+            "\x1b]8;;https://www.vim.org\x1b\\vim\x1b]8;;\x1b\\"
+        );
+    }
+
+    #[test]
+    fn version_orders_read_as_words() {
+        assert_eq!(version_relation(Ordering::Less), "older");
+        assert_eq!(version_relation(Ordering::Equal), "equal");
+        assert_eq!(version_relation(Ordering::Greater), "newer");
+    }
+
+    #[test]
+    fn pad_to_max_is_left_justified_names() {
+        let vi = TestPackage::new("vi");
+        let emacs = TestPackage::new("emacs");
+        let lookup = TestLookup::default();
+        assert!(matches!(
+            single_part("%{n:pad-to-max}"),
+            Format::Modified(inner, Modifier::LeftJustify) if matches!(*inner, Format::Name)
+        ));
+        assert_eq!(
+            render_all("%{n:pad-to-max}|", &[&vi, &emacs], &lookup, false),
+            ["vi   |", "emacs|"]
+        );
+    }
+
+    #[test]
+    fn widths_pad_without_cutting_off() {
+        let vim = TestPackage::new("vim");
+        let firmware = TestPackage::new("linux-firmware");
+        assert_eq!(render("%{n:8}|", &vim), "vim     |");
+        assert_eq!(render("%{n:>8}|", &vim), "     vim|");
+        assert_eq!(render("%{n:8}|", &firmware), "linux-firmware|");
+        assert_eq!(render("%{n:>8}|", &firmware), "linux-firmware|");
+        for text in ["%{n:-8}", "%{n:>}", "%{n:8x}", "%{n:>-8}"] {
+            assert!(CompiledFormat::compile(text).is_err(), "{}", text);
+        }
+    }
+
+    // the widths count characters, a cut never splits one
+    #[test]
+    fn wrap_and_trunc_count_multibyte_characters() {
+        let pkg = TestPackage {
+            desc: Some("größe über naïveté"),
+            ..TestPackage::new("pkg")
+        };
+        assert_eq!(render("%{s:5:trunc}", &pkg), "größe");
+        assert_eq!(render("%{s:6:trunc}", &pkg), "größe ");
+        assert_eq!(render("%{s:5:wrap}", &pkg), "größe\nüber\nnaïveté");
+        assert_eq!(render("%{s:10:wrap}", &pkg), "größe über\nnaïveté");
+    }
+
+    #[test]
+    fn ellipses_count_towards_the_width() {
+        let firmware = TestPackage::new("linux-firmware");
+        assert_eq!(render("%{n:8:ellipsis}", &firmware), "linux...");
+        assert_eq!(render("%{n:8:ellipsis=~}", &firmware), "linux-f~");
+        assert_eq!(render("%{n:8:ellipsis=…}", &firmware), "linux-f…");
+        // cuts off like trunc
+        assert_eq!(render("%{n:8:ellipsis=}", &firmware), "linux-fi");
+        assert_eq!(render("%{n:14:ellipsis=~}", &firmware), "linux-firmware");
+    }
+
+    #[test]
+    fn conditionals_pick_a_branch_by_reason() {
+        assert!(matches!(
+            single_part("%{?r=Explicit:yes|no}"),
+            Format::Conditional {
+                condition: Condition::Reason(PackageReason::Explicit),
+                then: "yes",
+                otherwise: "no",
+            }
+        ));
+        let vim = TestPackage::new("vim");
+        let gpm = TestPackage::depend("gpm");
+        assert_eq!(render("%{?r=Explicit:yes|no}", &vim), "yes");
+        assert_eq!(render("%{?r=Explicit:yes|no}", &gpm), "no");
+        assert_eq!(render("%{?r=Depend:dep|}", &vim), "");
+        assert_eq!(render("%{?r=Depend:dep|}", &gpm), "dep");
+        for text in [
+            "%{?x=Explicit:yes|no}",
+            "%{?r=Optional:yes|no}",
+            "%{?r=Explicit:yes}",
+            "%{?r:yes|no}",
+        ] {
+            assert!(CompiledFormat::compile(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn null_escape_renders_a_null_byte() {
+        assert_eq!(render("%n\\0", &TestPackage::new("vim")), "vim\0");
+    }
+
+    #[test]
+    fn tab_and_newline_escapes_render_as_is() {
+        assert_eq!(
+            render("%n\\t%v\\n", &TestPackage::new("vim")),
+            "vim\t1.0-1\n"
+        );
+    }
+
+    #[test]
+    fn listed_formats_include_every_token() {
+        let listing = print_format_tokens();
+        let lines: Vec<_> = listing.lines().collect();
+        assert!(lines.iter().any(|line| line.contains("%n|%{n}")));
+        assert!(lines.iter().any(|line| line.contains("%v|%{v}")));
+        assert_eq!(
+            lines.len(),
+            TOKENS.len() + ESCAPES.len() + ARGUMENT_TOKENS.len() + MODIFIERS.len()
+        );
+    }
+
+    #[test]
+    fn localized_summaries_fall_back_to_the_default() {
+        assert!(matches!(
+            single_part("%{s:lang=de}"),
+            Format::LocalizedSummary("de")
+        ));
+        let vim = TestPackage {
+            desc: Some("Vi Improved, a text editor"),
+            localized_descs: vec![("de", "Vi Improved, ein Texteditor")],
+            ..TestPackage::new("vim")
+        };
+        assert_eq!(render("%{s:lang=de}", &vim), "Vi Improved, ein Texteditor");
+        assert_eq!(render("%{s:lang=fr}", &vim), "Vi Improved, a text editor");
+        assert_eq!(render("%{s:lang=fr}", &TestPackage::new("nano")), "");
+        for text in ["%{s:lang=}", "%{s:lang=de DE}"] {
+            assert!(CompiledFormat::compile(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn unknown_dates_render_empty() {
+        let vim = TestPackage::new("vim");
+        assert_eq!(render("%i|%b|%{age}|%{date:%Y}", &vim), "|||");
+        let vim = TestPackage {
+            install_date: Some(1_700_000_000),
+            ..vim
+        };
+        assert_eq!(render("%{date:%Y}", &vim), "2023");
+    }
+
+    #[test]
+    fn install_dates_take_a_strftime_pattern() {
+        assert!(matches!(
+            single_part("%{i:date=%Y-%m}"),
+            Format::Date {
+                field: DateField::Install,
+                pattern: "%Y-%m",
+            }
+        ));
+        let vim = TestPackage {
+            install_date: Some(1_700_000_000),
+            ..TestPackage::new("vim")
+        };
+        assert_eq!(render("%{i:date=year %Y}", &vim), "year 2023");
+        for text in ["%{i:date=}", "%{i:date=%Q}", "%{i:date=%Y %}"] {
+            assert!(CompiledFormat::compile(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn compression_needs_both_sizes() {
+        assert_eq!(compression_ratio(256, 1024), Some(25.0));
+        assert_eq!(compression_ratio(0, 1024), None);
+        assert_eq!(compression_ratio(256, 0), None);
+        let lookup = TestLookup {
+            download_sizes: vec![("vim", 512)],
+            ..Default::default()
+        };
+        let vim = TestPackage {
+            isize: 2048,
+            ..TestPackage::new("vim")
+        };
+        let foreign = TestPackage {
+            isize: 2048,
+            ..TestPackage::new("yay")
+        };
+        assert_eq!(
+            render_all("%{compression}", &[&vim, &foreign], &lookup, false),
+            ["25.00%", ""]
+        );
+    }
+
+    #[test]
+    fn json_escapes_quotes_and_control_characters() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("say \"hi\"\\\n\t\r\u{1}"),
+            "\"say \\\"hi\\\"\\\\\\n\\t\\r\\u0001\""
+        );
+        let vim = TestPackage {
+            desc: Some("Vi \"Improved\""),
+            ..TestPackage::new("vim")
+        };
+        let gpm = TestPackage::depend("gpm");
+        assert_eq!(
+            package_json(&gpm, Some(&[&vim])),
+            "{\"name\":\"gpm\",\"version\":\"1.0-1\",\"reason\":\"Depend\",\"description\":null,\
+             \"required_by\":[{\"name\":\"vim\",\"reason\":\"Explicit\"}]}"
+        );
+        assert_eq!(
+            package_json::<TestPackage>(&vim, None),
+            "{\"name\":\"vim\",\"version\":\"1.0-1\",\"reason\":\"Explicit\",\
+             \"description\":\"Vi \\\"Improved\\\"\"}"
+        );
+    }
+
+    #[test]
+    fn streamed_lines_match_buffered_ones() {
+        let printed = |sink| {
+            let mut out = Vec::new();
+            let mut printer = Printer::with_sink(sink, false, false, &mut out);
+            for line in ["linux", "linux-firmware", "glibc 2.40"] {
+                printer.push(line.to_string()).unwrap();
+            }
+            printer.finish().unwrap();
+            out
+        };
+        let streamed = printed(Sink::Streaming);
+        assert_eq!(streamed, b"linux\nlinux-firmware\nglibc 2.40\n");
+        assert_eq!(streamed, printed(Sink::Buffered(Vec::new())));
+    }
+
+    #[test]
+    fn print0_terminates_every_entry_with_a_null_byte() {
+        let mut out = Vec::new();
+        let mut printer = Printer::with_sink(Sink::Buffered(Vec::new()), true, false, &mut out);
+        for line in ["linux", "linux-firmware"] {
+            printer.push(line.to_string()).unwrap();
+        }
+        printer.finish().unwrap();
+        assert_eq!(out, b"linux\0linux-firmware\0");
+    }
+}
@@ -1,9 +1,15 @@
+use alpm::PackageReason;
 use std::error;
 use std::fmt;
+use std::io::{self, BufRead};
+
+// this query is replaced by the queries read from stdin
+pub const STDIN_QUERY: &str = "-";
 
 #[derive(Debug)]
 pub enum ParseError {
     InvalidProperty(String),
+    InvalidValue(String, String),
     SyntaxError,
 }
 
@@ -14,6 +20,7 @@ impl fmt::Display for ParseError {
         use ParseError::*;
         match self {
             InvalidProperty(prop) => write!(f, "property not supported: {}", prop),
+            InvalidValue(prop, value) => write!(f, "invalid value for {}: {}", prop, value),
             SyntaxError => write!(f, "invalid syntax"),
         }
     }
@@ -22,6 +29,39 @@ impl fmt::Display for ParseError {
 #[derive(Debug)]
 pub enum Query {
     PackageName(String),
+    Glob(String),
+    Description(String),
+    ProvidesCount(Comparison),
+    Reason(PackageReason),
+    Group(String),
+}
+
+// a number compared against, written as >N, <N or N
+#[derive(Debug)]
+pub enum Comparison {
+    Less(usize),
+    Equal(usize),
+    Greater(usize),
+}
+
+impl Comparison {
+    fn parse(value: &str) -> Option<Comparison> {
+        if let Some(n) = value.strip_prefix(">") {
+            n.parse().ok().map(Comparison::Greater)
+        } else if let Some(n) = value.strip_prefix("<") {
+            n.parse().ok().map(Comparison::Less)
+        } else {
+            value.parse().ok().map(Comparison::Equal)
+        }
+    }
+
+    pub fn test(&self, n: usize) -> bool {
+        match self {
+            Comparison::Less(m) => n < *m,
+            Comparison::Equal(m) => n == *m,
+            Comparison::Greater(m) => n > *m,
+        }
+    }
 }
 
 impl Query {
@@ -30,13 +70,228 @@ impl Query {
         S: AsRef<str>,
     {
         let Some((prop, value)) = query.as_ref().split_once(":") else {
-            return Ok(Query::PackageName(query.as_ref().to_string()));
+            return Ok(Query::name(query.as_ref()));
         };
 
         match prop {
-            "package" => Ok(Query::PackageName(value.to_string())),
+            "package" => Ok(Query::name(value)),
+            "desc" => Ok(Query::Description(value.to_string())),
+            "provides-count" => Comparison::parse(value)
+                .map(Query::ProvidesCount)
+                .ok_or_else(|| ParseError::InvalidValue(prop.to_string(), value.to_string())),
+            "group" => Ok(Query::Group(value.to_string())),
+            "reason" => match value {
+                "explicit" => Ok(Query::Reason(PackageReason::Explicit)),
+                "dependency" => Ok(Query::Reason(PackageReason::Depend)),
+                _ => Err(ParseError::InvalidValue(
+                    prop.to_string(),
+                    value.to_string(),
+                )),
+            },
             _ if prop.trim() != prop => Err(ParseError::SyntaxError),
             _ => Err(ParseError::InvalidProperty(prop.to_string())),
         }
     }
+
+    fn name(value: &str) -> Query {
+        match glob_literal(value) {
+            Some(name) => Query::PackageName(name),
+            None => Query::Glob(value.to_string()),
+        }
+    }
+}
+
+enum GlobPart {
+    // `*`, any run of characters
+    Star,
+    // `?`, any single character
+    Question,
+    Literal(char),
+}
+
+// a backslash makes the next character literal, so `\*` matches an asterisk
+fn glob_parts(pattern: &str) -> Vec<GlobPart> {
+    let mut parts = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        parts.push(match c {
+            '\\' => GlobPart::Literal(chars.next().unwrap_or('\\')),
+            '*' => GlobPart::Star,
+            '?' => GlobPart::Question,
+            c => GlobPart::Literal(c),
+        });
+    }
+    parts
+}
+
+// the pattern with its escapes removed, if it has no wildcards
+fn glob_literal(pattern: &str) -> Option<String> {
+    glob_parts(pattern)
+        .into_iter()
+        .map(|part| match part {
+            GlobPart::Literal(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+// the whole text has to match, on a mismatch the last `*` takes one more character and the
+// rest is tried again
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let parts = glob_parts(pattern);
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // the last star seen and the text position it was matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match parts.get(p) {
+            Some(GlobPart::Star) => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(GlobPart::Question) => {
+                p += 1;
+                t += 1;
+            }
+            Some(GlobPart::Literal(c)) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    parts[p..].iter().all(|part| matches!(part, GlobPart::Star))
+}
+
+// without a wildcard the pattern is searched anywhere in the description, case is ignored
+pub fn description_matches(pattern: &str, desc: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let desc = desc.to_lowercase();
+    match glob_literal(&pattern) {
+        Some(literal) => desc.contains(&literal),
+        None => glob_match(&pattern, &desc),
+    }
+}
+
+// one query per line, blank lines are skipped
+pub fn read_queries<R: BufRead>(reader: R) -> io::Result<Vec<String>> {
+    let mut queries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            queries.push(line.trim().to_string());
+        }
+    }
+    Ok(queries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("python-*", "python-numpy"));
+        assert!(glob_match("lib?", "libx"));
+        assert!(glob_match("*-git", "neovim-git"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("lib?", "lib"));
+        assert!(!glob_match("python-*", "python"));
+        assert!(!glob_match("a*b", "acbd"));
+    }
+
+    #[test]
+    fn glob_match_escaped_star_is_literal() {
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+    }
+
+    #[test]
+    fn description_matches_anywhere_ignoring_case() {
+        assert!(description_matches(
+            "Editor",
+            "A text editor for the terminal"
+        ));
+        assert!(description_matches(
+            "*text*term*",
+            "A Text editor for the terminal"
+        ));
+        assert!(!description_matches("*text", "A text editor"));
+    }
+
+    #[test]
+    fn comparison_parses_and_tests() {
+        let more = Comparison::parse(">2").unwrap();
+        assert!(more.test(3) && !more.test(2));
+        let less = Comparison::parse("<2").unwrap();
+        assert!(less.test(1) && !less.test(2));
+        let exact = Comparison::parse("2").unwrap();
+        assert!(exact.test(2) && !exact.test(3));
+        assert!(Comparison::parse(">").is_none());
+        assert!(Comparison::parse("-1").is_none());
+    }
+
+    #[test]
+    fn read_queries_skips_blank_lines() {
+        let input = "linux\n\n  vim  \n".as_bytes();
+        assert_eq!(read_queries(input).unwrap(), ["linux", "vim"]);
+    }
+
+    #[test]
+    fn names_with_wildcards_are_globs() {
+        assert!(matches!(Query::parse(&"linux").unwrap(), Query::PackageName(n) if n == "linux"));
+        assert!(matches!(Query::parse(&"python-*").unwrap(), Query::Glob(g) if g == "python-*"));
+        assert!(matches!(Query::parse(&"package:lib?").unwrap(), Query::Glob(g) if g == "lib?"));
+        assert!(matches!(Query::parse(&"c\\*\\*").unwrap(), Query::PackageName(n) if n == "c**"));
+    }
+
+    #[test]
+    fn glob_selects_from_a_package_set() {
+        let names = [
+            "python",
+            "python-numpy",
+            "python-requests",
+            "python2",
+            "cython",
+        ];
+        let matched: Vec<_> = names
+            .into_iter()
+            .filter(|name| glob_match("python-*", name))
+            .collect();
+        assert_eq!(matched, ["python-numpy", "python-requests"]);
+    }
+
+    #[test]
+    fn reasons_parse_from_their_names() {
+        assert!(matches!(
+            Query::parse(&"reason:explicit").unwrap(),
+            Query::Reason(PackageReason::Explicit)
+        ));
+        assert!(matches!(
+            Query::parse(&"reason:dependency").unwrap(),
+            Query::Reason(PackageReason::Depend)
+        ));
+        assert!(matches!(
+            Query::parse(&"reason:foo"),
+            Err(ParseError::InvalidValue(prop, value)) if prop == "reason" && value == "foo"
+        ));
+    }
+
+    #[test]
+    fn groups_parse_as_is() {
+        assert!(matches!(
+            Query::parse(&"group:base-devel").unwrap(),
+            Query::Group(group) if group == "base-devel"
+        ));
+    }
 }
@@ -0,0 +1,68 @@
+use std::time::{Duration, Instant};
+
+// wall-clock time spent in each phase of a run, a phase that is entered several times adds up
+pub struct Timings {
+    enabled: bool,
+    phases: Vec<(&'static str, Duration)>,
+    last: Instant,
+}
+
+impl Timings {
+    pub fn new(enabled: bool) -> Timings {
+        Timings {
+            enabled,
+            phases: Vec::new(),
+            last: Instant::now(),
+        }
+    }
+
+    // ends the running phase and counts the time since the previous lap towards it
+    pub fn lap(&mut self, phase: &'static str) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        self.add(phase, now - self.last);
+        self.last = now;
+    }
+
+    fn add(&mut self, phase: &'static str, duration: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((phase, duration)),
+        }
+    }
+
+    pub fn report(&self) {
+        for (phase, duration) in &self.phases {
+            eprintln!("{}: {:.3} ms", phase, duration.as_secs_f64() * 1000.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_phases_add_up_in_first_seen_order() {
+        let mut timings = Timings::new(true);
+        timings.add("traversal", Duration::from_millis(2));
+        timings.add("rendering", Duration::from_millis(1));
+        timings.add("traversal", Duration::from_millis(3));
+        assert_eq!(
+            timings.phases,
+            [
+                ("traversal", Duration::from_millis(5)),
+                ("rendering", Duration::from_millis(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_timings_record_nothing() {
+        let mut timings = Timings::new(false);
+        timings.lap("traversal");
+        assert!(timings.phases.is_empty());
+    }
+}